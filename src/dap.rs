@@ -0,0 +1,506 @@
+//! Debug Adapter Protocol server, for editors.
+//!
+//! Start the emulator with `--dap <port>` and point the editor's debug configuration at
+//! that port (`debugServer` in VS Code). `launch` takes the `program` to load,
+//! `stopOnEntry`, and `entry: false` to keep the reset vector. Assembly sources get
+//! breakpoints by line and a source position for PC; other formats can still be stepped,
+//! with registers and memory available
+use crate::emulator::assembler::{self, Assembly};
+use crate::emulator::debugger::{self, Breakpoint, Condition, StopReason};
+use crate::emulator::loader::{Format, LoadOptions};
+use crate::emulator::{Address, System};
+use crate::handler::{Cmd, ThreadedEmulator};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// The CPU is the only thread
+const THREAD_ID: u64 = 1;
+const REGISTERS_REF: u64 = 1;
+const SYMBOLS_REF: u64 = 2;
+
+/// Listens on localhost:`port`, serving one editor at a time
+pub fn serve(port: u16, emulator: &ThreadedEmulator) -> io::Result<thread::JoinHandle<()>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let tcmd = emulator.tcmd.clone();
+    let system = emulator.system.clone();
+    let output: Output = Arc::default();
+    // Stops become events for whichever editor is connected
+    {
+        let stops = emulator.subscribe();
+        let output = output.clone();
+        thread::spawn(move || {
+            for reason in stops {
+                if let Some(writer) = output.lock().expect("Couldn't lock the output").as_mut() {
+                    // A closed connection is noticed by its session
+                    let _ = writer.send(stopped(reason));
+                }
+            }
+        });
+    }
+    println!("Waiting for a DAP client on port {}", port);
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| {
+                *output.lock().expect("Couldn't lock the output") = Some(Writer {
+                    stream: stream.try_clone()?,
+                    seq: 1,
+                });
+                Session {
+                    reader: BufReader::new(stream),
+                    output: output.clone(),
+                    tcmd: tcmd.clone(),
+                    system: system.clone(),
+                    assembly: None,
+                    breakpoints: HashMap::new(),
+                    stop_on_entry: false,
+                }
+                .run()
+            });
+            *output.lock().expect("Couldn't lock the output") = None;
+            match result {
+                Ok(()) => println!("DAP client disconnected"),
+                Err(e) => println!("DAP connection closed: {}", e),
+            }
+        }
+    }))
+}
+
+/// Where responses and events go. Shared with the thread that reports stops
+type Output = Arc<Mutex<Option<Writer>>>;
+
+struct Writer {
+    stream: TcpStream,
+    seq: u64,
+}
+impl Writer {
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = message.to_string();
+        write!(
+            self.stream,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.stream.flush()
+    }
+}
+
+fn event(name: &str, body: Value) -> Value {
+    json!({ "type": "event", "event": name, "body": body })
+}
+fn stopped(reason: StopReason) -> Value {
+    let kind = match reason {
+        StopReason::Requested => "pause",
+        StopReason::Breakpoint(_) => "breakpoint",
+        StopReason::Watchpoint(_) => "data breakpoint",
+        StopReason::Fault(_) => "exception",
+        _ => "step",
+    };
+    event(
+        "stopped",
+        json!({
+            "reason": kind,
+            "description": reason.to_string(),
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        }),
+    )
+}
+
+struct Session {
+    reader: BufReader<TcpStream>,
+    output: Output,
+    tcmd: mpsc::Sender<Cmd>,
+    system: Arc<Mutex<System>>,
+    /// The launched program, when it's an assembly source
+    assembly: Option<Assembly>,
+    /// Breakpoints set on each source, to replace them when it changes
+    breakpoints: HashMap<PathBuf, Vec<Address>>,
+    stop_on_entry: bool,
+}
+impl Session {
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(request) = self.read_message()? {
+            let command = request["command"].as_str().unwrap_or_default().to_string();
+            let result = self.handle(&command, &request["arguments"]);
+            let mut response = json!({
+                "type": "response",
+                "request_seq": request["seq"],
+                "command": command,
+                "success": result.is_ok(),
+            });
+            match result {
+                Ok(body) => response["body"] = body,
+                Err(message) => response["message"] = json!(message),
+            }
+            self.send(response)?;
+            match command.as_str() {
+                "initialize" => self.send(event("initialized", json!({})))?,
+                "configurationDone" if self.stop_on_entry => {
+                    let mut entry = stopped(StopReason::Requested);
+                    entry["body"]["reason"] = json!("entry");
+                    entry["body"]["description"] = json!("Entry");
+                    self.send(entry)?
+                }
+                "disconnect" => return Ok(()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /* #region Framing */
+    /// Reads the next message. `None` when the client closed the connection
+    fn read_message(&mut self) -> io::Result<Option<Value>> {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if self.reader.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+        let length = length.ok_or_else(|| invalid("Missing Content-Length"))?;
+        let mut body = vec![0; length];
+        self.reader.read_exact(&mut body)?;
+        serde_json::from_slice(&body)
+            .map(Some)
+            .map_err(|e| invalid(&e.to_string()))
+    }
+    fn send(&self, message: Value) -> io::Result<()> {
+        match self
+            .output
+            .lock()
+            .expect("Couldn't lock the output")
+            .as_mut()
+        {
+            Some(writer) => writer.send(message),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+    /* #endregion */
+
+    /// The body of the response, or why the request failed
+    fn handle(&mut self, command: &str, args: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsReadMemoryRequest": true,
+                "supportsEvaluateForHovers": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "configurationDone" => {
+                if !self.stop_on_entry {
+                    self.command(Cmd::Run)?;
+                }
+                Ok(Value::Null)
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "6502" }] })),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                { "name": "Symbols", "variablesReference": SYMBOLS_REF, "expensive": false },
+            ] })),
+            "variables" => {
+                Ok(json!({ "variables": self.variables(args["variablesReference"].as_u64()) }))
+            }
+            "continue" => {
+                self.command(Cmd::Run)?;
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            // Stepping one cycle runs one instruction, and reports the stop
+            "stepIn" => self.command(Cmd::RunFor(1)).map(|_| Value::Null),
+            "next" => {
+                let cmd = match debugger::return_address(&self.lock()) {
+                    Some(_) => Cmd::StepOver,
+                    None => Cmd::RunFor(1),
+                };
+                self.command(cmd).map(|_| Value::Null)
+            }
+            "stepOut" => self.command(Cmd::StepOut).map(|_| Value::Null),
+            "pause" => self.command(Cmd::Stop).map(|_| Value::Null),
+            "readMemory" => self.read_memory(args),
+            "evaluate" => {
+                let expression = args["expression"].as_str().unwrap_or_default();
+                let value = self
+                    .evaluate(expression)
+                    .ok_or_else(|| format!("Unknown symbol {}", expression))?;
+                let result = match value {
+                    0..=0xFF => format!("${:02X}", value),
+                    _ => format!("${:04X}", value),
+                };
+                Ok(json!({ "result": result, "variablesReference": 0 }))
+            }
+            "disconnect" => self.command(Cmd::Stop).map(|_| Value::Null),
+            _ => Err(format!("Unsupported request {}", command)),
+        }
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"]
+            .as_str()
+            .ok_or("The launch configuration needs a program")?;
+        let path = PathBuf::from(program);
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        // The emulator assembles it again when loading, and on every reset
+        self.assembly = if Format::guess(&path) == Format::Assembly {
+            Some(assembler::assemble_file(&path).map_err(|e| e.to_string())?)
+        } else {
+            None
+        };
+        let options = LoadOptions {
+            format: None,
+            // Like `--entry`, but on by default since launching means running it
+            set_reset_vector: args["entry"].as_bool().unwrap_or(true),
+        };
+        self.command(Cmd::Load(path, options))?;
+        Ok(Value::Null)
+    }
+
+    /// Puts each breakpoint on the first line with code at or after it
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"]
+            .as_str()
+            .map(canonical)
+            .ok_or("Breakpoints need a source path")?;
+        for addr in self.breakpoints.remove(&path).unwrap_or_default() {
+            self.command(Cmd::RemoveBreakpoint(addr))?;
+        }
+        let lines = match &self.assembly {
+            Some(assembly) => assembly
+                .lines
+                .iter()
+                .filter(|line| canonical(&line.file) == path)
+                .collect(),
+            None => vec![],
+        };
+        let mut addresses = vec![];
+        let mut breakpoints = vec![];
+        for requested in args["breakpoints"].as_array().unwrap_or(&vec![]) {
+            let line = requested["line"].as_u64().unwrap_or(0) as usize;
+            let condition = match requested["condition"].as_str() {
+                Some(text) if !text.trim().is_empty() => match text.parse::<Condition>() {
+                    Ok(condition) => Some(condition),
+                    Err(e) => {
+                        breakpoints.push(json!({ "verified": false, "message": e }));
+                        continue;
+                    }
+                },
+                _ => None,
+            };
+            let found = lines
+                .iter()
+                .filter(|source| source.line >= line)
+                .min_by_key(|source| source.line);
+            match found {
+                Some(source) => {
+                    self.command(Cmd::AddBreakpoint(Breakpoint {
+                        addr: source.address,
+                        condition,
+                    }))?;
+                    addresses.push(source.address);
+                    breakpoints.push(json!({ "verified": true, "line": source.line }));
+                }
+                None => breakpoints.push(json!({
+                    "verified": false,
+                    "message": "No code at or after this line",
+                })),
+            }
+        }
+        self.breakpoints.insert(path, addresses);
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn stack_trace(&self) -> Value {
+        let pc = self.lock().registers.PC;
+        let mut frame = json!({
+            "id": 0,
+            "name": self.symbolize(pc),
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format!("0x{:04X}", *pc),
+        });
+        let source = self.assembly.as_ref().and_then(|assembly| {
+            assembly
+                .lines
+                .iter()
+                .find(|line| line.address <= pc && *pc < *line.address + line.size)
+        });
+        if let Some(source) = source {
+            frame["source"] = json!({
+                "name": source.file.file_name().map(|name| name.to_string_lossy()),
+                "path": canonical(&source.file),
+            });
+            frame["line"] = json!(source.line);
+            frame["column"] = json!(1);
+        }
+        json!({ "stackFrames": [frame], "totalFrames": 1 })
+    }
+
+    fn variables(&self, reference: Option<u64>) -> Vec<Value> {
+        let variable = |name: &str, value: String| {
+            json!({
+                "name": name,
+                "value": value,
+                "variablesReference": 0,
+            })
+        };
+        match reference {
+            Some(REGISTERS_REF) => {
+                let system = self.lock();
+                let r = &system.registers;
+                let flags: String = "NV-BDIZC"
+                    .chars()
+                    .enumerate()
+                    .map(|(i, flag)| match r.flags & (0x80 >> i) {
+                        0 => flag.to_ascii_lowercase(),
+                        _ => flag,
+                    })
+                    .collect();
+                vec![
+                    variable("A", format!("${:02X}", r.A)),
+                    variable("X", format!("${:02X}", r.X)),
+                    variable("Y", format!("${:02X}", r.Y)),
+                    variable("S", format!("${:02X}", r.S)),
+                    variable("P", format!("${:02X} {}", r.flags, flags)),
+                    variable("PC", format!("${:04X}", *r.PC)),
+                    variable("Cycles", system.cycles.to_string()),
+                ]
+            }
+            Some(SYMBOLS_REF) => match &self.assembly {
+                Some(assembly) => assembly
+                    .symbols
+                    .iter()
+                    .map(|(name, value)| variable(name, format!("${:04X}", value)))
+                    .collect(),
+                None => vec![],
+            },
+            _ => vec![],
+        }
+    }
+
+    /// `memoryReference` is an address, like `0x0200` or `$0200`
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let reference = args["memoryReference"].as_str().unwrap_or_default();
+        let start = self
+            .evaluate(reference)
+            .ok_or_else(|| format!("Invalid memory reference {}", reference))?
+            as i64
+            + args["offset"].as_i64().unwrap_or(0);
+        let count = args["count"].as_u64().unwrap_or(0) as i64;
+        // Reads past the end of the address space are cut short
+        let end = (start + count).min(0x10000);
+        if !(0..=0xFFFF).contains(&start) {
+            return Err(format!("Address {} is out of range", start));
+        }
+        let system = self.lock();
+        let data: Vec<u8> = (start..end)
+            .map(|addr| system.bus.peek(Address(addr as usize)))
+            .collect();
+        Ok(json!({
+            "address": format!("0x{:04X}", start),
+            "data": base64(&data),
+            "unreadableBytes": count - data.len() as i64,
+        }))
+    }
+
+    /// Registers, symbols and addresses
+    fn evaluate(&self, expression: &str) -> Option<usize> {
+        let expression = expression.trim();
+        let system = self.lock();
+        let r = &system.registers;
+        let register = match expression.to_ascii_uppercase().as_str() {
+            "A" => Some(r.A as usize),
+            "X" => Some(r.X as usize),
+            "Y" => Some(r.Y as usize),
+            "S" | "SP" => Some(r.S as usize),
+            "P" => Some(r.flags as usize),
+            "PC" => Some(*r.PC),
+            _ => None,
+        };
+        let symbol = || {
+            self.assembly
+                .as_ref()
+                .and_then(|assembly| assembly.symbols.get(expression))
+                .map(|value| *value as usize)
+        };
+        let hex = || {
+            let digits = expression.strip_prefix("0x").unwrap_or(expression);
+            usize::from_str_radix(digits, 16).ok()
+        };
+        register
+            .or_else(symbol)
+            .or_else(|| expression.parse::<Address>().ok().map(|addr| *addr))
+            .or_else(hex)
+    }
+
+    /// The closest label before `addr`, like `loop+2`
+    fn symbolize(&self, addr: Address) -> String {
+        let label = self.assembly.as_ref().and_then(|assembly| {
+            assembly
+                .symbols
+                .iter()
+                .filter(|(_, value)| **value as usize <= *addr)
+                .max_by_key(|(_, value)| **value)
+        });
+        match label {
+            Some((name, value)) if *addr == *value as usize => name.clone(),
+            Some((name, value)) => format!("{}+{}", name, *addr - *value as usize),
+            None => format!("${:04X}", *addr),
+        }
+    }
+
+    fn command(&self, cmd: Cmd) -> Result<(), String> {
+        self.tcmd
+            .send(cmd)
+            .map_err(|_| "The emulator stopped".to_string())
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, System> {
+        self.system
+            .lock()
+            .unwrap_or_else(|e| panic!("Error acquiring lock for the system. Error: {}", e))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Paths from the editor and the assembler are compared once resolved
+fn canonical<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn base64(data: &[u8]) -> String {
+    const DIGITS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(DIGITS[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
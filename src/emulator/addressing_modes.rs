@@ -1,21 +1,26 @@
-use std::convert::{From, Into};
+use super::error::CpuErrorKind;
+use std::convert::{From, Into, TryFrom};
 
 #[derive(Copy, Clone, PartialEq, PartialOrd, Debug)]
 pub struct Address(pub usize);
-impl From<usize> for Address {
-    fn from(v: usize) -> Self {
+impl TryFrom<usize> for Address {
+    type Error = CpuErrorKind;
+    fn try_from(v: usize) -> Result<Self, Self::Error> {
         if v > 0xFFFF {
-            panic!("Address is bigger than 0x10000 (Got 0x{:04X})", v);
+            return Err(CpuErrorKind::AddressOverflow(
+                i64::try_from(v).unwrap_or(i64::MAX),
+            ));
         }
-        Self(v)
+        Ok(Self(v))
     }
 }
-impl From<i32> for Address {
-    fn from(v: i32) -> Self {
-        if v < 0 || v > 0xFFFF {
-            panic!("Can't use {}i32 as address", v);
+impl TryFrom<i32> for Address {
+    type Error = CpuErrorKind;
+    fn try_from(v: i32) -> Result<Self, Self::Error> {
+        if !(0..=0xFFFF).contains(&v) {
+            return Err(CpuErrorKind::AddressOverflow(v as i64));
         }
-        Address((v & 0xFFFF) as usize)
+        Ok(Address(v as usize))
     }
 }
 impl From<u16> for Address {
@@ -49,17 +54,18 @@ impl std::ops::DerefMut for Address {
         &mut self.0
     }
 }
-impl std::ops::Add for Address {
-    type Output = Self;
-    fn add(self, rhs: Self) -> Self {
-        let result = *self + *rhs;
-        if result > 0xFFFF {
-            panic!(
-                "Resulting op between Address and 0x{:04X} is bigger than 0xFFFF",
-                *rhs
-            );
-        }
-        Address(result)
+impl std::str::FromStr for Address {
+    type Err = std::num::ParseIntError;
+    /// Accepts hexadecimal with a `$` or `0x` prefix, and decimal otherwise
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let value = if let Some(hex) = text.strip_prefix('$') {
+            u16::from_str_radix(hex, 16)?
+        } else if let Some(hex) = text.strip_prefix("0x") {
+            u16::from_str_radix(hex, 16)?
+        } else {
+            text.parse::<u16>()?
+        };
+        Ok(value.into())
     }
 }
 impl Address {
@@ -70,7 +76,14 @@ impl Address {
         Address(hi | lo)
     }
     pub fn next(&self) -> Self {
-        Address(self.0 + 1)
+        self.wrapping_add(1usize)
+    }
+    pub fn wrapping_add<I: Into<usize>>(self, rhs: I) -> Self {
+        Address((*self + rhs.into()) & 0xFFFF)
+    }
+    /// `AddressOverflow` when the result is past $FFFF
+    pub fn checked_add<I: Into<usize>>(self, rhs: I) -> Result<Self, CpuErrorKind> {
+        Address::try_from(self.0.saturating_add(rhs.into()))
     }
 }
 
@@ -82,6 +95,15 @@ pub fn get_size(addr_mode: AddressingMode) -> usize {
 //1,  3,   3,   3,   2,  1,  3,   2,   2,  2,  2,   2,   2
 pub static OP_SIZES: [usize; 13] = [1, 3, 3, 3, 2, 1, 3, 2, 2, 2, 2, 2, 2];
 
+/// What an instruction operates on, once its addressing mode has been resolved
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Operand {
+    None,             // IMPL
+    Accumulator,      // A
+    Immediate(u8),    // IMM
+    Address(Address), // Effective address for every other mode (REL: branch target)
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum AddressingMode {
     A = 0, // LSR A
@@ -0,0 +1,865 @@
+//! A two pass assembler for the documented 6502 instructions.
+//!
+//! ```text
+//! SCREEN = $0200
+//!         .org $0600
+//! start:  LDX #0
+//! loop:   LDA colors,X
+//!         STA SCREEN,X
+//!         INX
+//!         CPX #colors_end - colors
+//!         BNE loop
+//!         BRK
+//! colors: .byte 1, 2, $03, %100, 'A', "text"
+//! colors_end:
+//!         .word start
+//!         .include "other.s"
+//! ```
+//! Numbers are decimal, `$` hex, `%` binary or `'c'` characters. Expressions take
+//! `+ - * / % & | ^ << >>`, parentheses, unary `- ~`, `<` (low byte), `>` (high byte)
+//! and `*` for the address of the current line.
+//! An operand wrapped in parentheses is indirect addressing, so `JMP (ptr)` is `IND`.
+//!
+//! The first pass picks every instruction's size: zero page is used when the operand
+//! is already known to fit in it, so forward references always get absolute addressing.
+//! The second pass evaluates the operands with every label known and emits the bytes.
+
+use super::addressing_modes::{get_size, Address, AddressingMode};
+use super::error::{AsmError, AsmErrorKind};
+use super::loader::Image;
+use super::opcodes::{get_code, OpcodeType};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Where code goes before the first `.org`
+pub const DEFAULT_ORIGIN: u16 = 0x0600;
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// An assembled program
+#[derive(Clone, Debug, Default)]
+pub struct Assembly {
+    /// Its entry point is the first instruction
+    pub image: Image,
+    /// Labels and constants
+    pub symbols: BTreeMap<String, u16>,
+    /// Every line that emitted bytes, in source order
+    pub lines: Vec<SourceLine>,
+}
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceLine {
+    pub file: PathBuf,
+    pub line: usize,
+    pub address: Address,
+    pub size: usize,
+}
+
+/// Assembles `source`. Includes are relative to the working directory
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    let mut statements = vec![];
+    parse_source(
+        Rc::new(PathBuf::from("<source>")),
+        source,
+        0,
+        &mut statements,
+    )?;
+    Assembler::default().run(&statements)
+}
+/// Assembles the file at `path`. Includes are relative to the including file
+pub fn assemble_file<P: AsRef<Path>>(path: P) -> Result<Assembly, AsmError> {
+    let path = path.as_ref();
+    let source = std::fs::read_to_string(path).map_err(|e| AsmError {
+        file: path.to_path_buf(),
+        line: 0,
+        kind: AsmErrorKind::Io(e),
+    })?;
+    let mut statements = vec![];
+    parse_source(Rc::new(path.to_path_buf()), &source, 0, &mut statements)?;
+    Assembler::default().run(&statements)
+}
+
+/* #region Parsing */
+/// How an instruction's operand was written
+#[derive(Clone, Debug, PartialEq)]
+enum Syntax {
+    None,              // CLC
+    Accumulator,       // ASL A
+    Immediate(String), // LDA #expr
+    Direct(String),    // LDA expr, BNE expr
+    IndexedX(String),  // LDA expr,X
+    IndexedY(String),  // LDA expr,Y
+    Indirect(String),  // JMP (expr)
+    IndirectX(String), // LDA (expr,X)
+    IndirectY(String), // LDA (expr),Y
+}
+#[derive(Clone, Debug, PartialEq)]
+enum Data {
+    Text(Vec<u8>),
+    Expr(String),
+}
+#[derive(Clone, Debug, PartialEq)]
+enum Kind {
+    Empty,
+    Org(String),
+    Constant(String, String),
+    Byte(Vec<Data>),
+    Word(Vec<String>),
+    /// Already expanded into the statements that follow it
+    Include(String),
+    Instruction(OpcodeType, Syntax),
+}
+struct Statement {
+    file: Rc<PathBuf>,
+    line: usize,
+    label: Option<String>,
+    kind: Kind,
+}
+impl Statement {
+    fn error(&self, kind: AsmErrorKind) -> AsmError {
+        AsmError {
+            file: self.file.to_path_buf(),
+            line: self.line,
+            kind,
+        }
+    }
+}
+
+fn syntax_error<T>(message: &str) -> Result<T, AsmErrorKind> {
+    Err(AsmErrorKind::Syntax(message.to_string()))
+}
+
+fn parse_source(
+    file: Rc<PathBuf>,
+    source: &str,
+    depth: usize,
+    statements: &mut Vec<Statement>,
+) -> Result<(), AsmError> {
+    for (i, text) in source.lines().enumerate() {
+        let error = |kind| AsmError {
+            file: file.to_path_buf(),
+            line: i + 1,
+            kind,
+        };
+        let (label, kind) = parse_line(text).map_err(error)?;
+        let include = match kind {
+            Kind::Include(ref name) => {
+                Some(file.parent().unwrap_or_else(|| Path::new("")).join(name))
+            }
+            _ => None,
+        };
+        // A label before an include points to its first byte
+        statements.push(Statement {
+            file: file.clone(),
+            line: i + 1,
+            label,
+            kind,
+        });
+        if let Some(path) = include {
+            if depth >= MAX_INCLUDE_DEPTH {
+                return Err(error(AsmErrorKind::IncludeDepth));
+            }
+            let source = std::fs::read_to_string(&path).map_err(|e| error(AsmErrorKind::Io(e)))?;
+            parse_source(Rc::new(path), &source, depth + 1, statements)?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_line(text: &str) -> Result<(Option<String>, Kind), AsmErrorKind> {
+    let text = strip_comment(text).trim();
+    let (label, rest) = split_label(text);
+    let label = label.map(str::to_string);
+    // NAME = expr
+    let ident_len = identifier_len(rest);
+    if ident_len > 0 && rest[ident_len..].trim_start().starts_with('=') {
+        if label.is_some() {
+            return syntax_error("A constant can't have a label");
+        }
+        let value = rest[ident_len..].trim_start()[1..].trim();
+        if value.is_empty() {
+            return syntax_error("Missing the constant's value");
+        }
+        let name = rest[..ident_len].to_string();
+        return Ok((None, Kind::Constant(name, value.to_string())));
+    }
+    let (word, args) = split_word(rest);
+    let kind = if word.is_empty() {
+        Kind::Empty
+    } else if word.starts_with('.') {
+        match word.to_ascii_lowercase().as_str() {
+            ".org" if !args.is_empty() => Kind::Org(args.to_string()),
+            ".org" => return syntax_error(".org needs an address"),
+            ".byte" | ".db" => Kind::Byte(
+                split_list(args)?
+                    .into_iter()
+                    .map(|item| match string_literal(item) {
+                        Some(text) => Data::Text(text),
+                        None => Data::Expr(item.to_string()),
+                    })
+                    .collect(),
+            ),
+            ".word" | ".dw" => {
+                Kind::Word(split_list(args)?.into_iter().map(str::to_string).collect())
+            }
+            ".include" => match string_literal(args) {
+                Some(name) => Kind::Include(String::from_utf8_lossy(&name).into_owned()),
+                None => return syntax_error(".include needs a quoted file name"),
+            },
+            _ => return Err(AsmErrorKind::UnknownDirective(word.to_string())),
+        }
+    } else {
+        let mnemonic = word.to_ascii_uppercase();
+        let opcode = OpcodeType::identify(&&mnemonic.as_str())
+            .map_err(|_| AsmErrorKind::UnknownMnemonic(word.to_string()))?;
+        Kind::Instruction(opcode, parse_operand(args)?)
+    };
+    Ok((label, kind))
+}
+
+fn parse_operand(text: &str) -> Result<Syntax, AsmErrorKind> {
+    if text.is_empty() {
+        return Ok(Syntax::None);
+    }
+    if text.eq_ignore_ascii_case("A") {
+        return Ok(Syntax::Accumulator);
+    }
+    if let Some(value) = text.strip_prefix('#') {
+        return Ok(Syntax::Immediate(value.trim().to_string()));
+    }
+    if text.starts_with('(') {
+        let close = matching_paren(text)
+            .ok_or_else(|| AsmErrorKind::Syntax("Unbalanced parentheses".to_string()))?;
+        let inner = &text[1..close];
+        let after = text[close + 1..].trim();
+        if after.is_empty() {
+            return Ok(match split_index(inner) {
+                Some((base, 'X')) => Syntax::IndirectX(base.to_string()),
+                Some(_) => return syntax_error("Only ($nn,X) can be indexed inside parentheses"),
+                None => Syntax::Indirect(inner.trim().to_string()),
+            });
+        }
+        let index = after.strip_prefix(',').map(str::trim);
+        if matches!(index, Some(index) if index.eq_ignore_ascii_case("Y")) {
+            return Ok(Syntax::IndirectY(inner.trim().to_string()));
+        }
+        // Something like (1 + 2) * 3, which isn't indirect
+    }
+    Ok(match split_index(text) {
+        Some((base, 'X')) => Syntax::IndexedX(base.to_string()),
+        Some((base, _)) => Syntax::IndexedY(base.to_string()),
+        None => Syntax::Direct(text.to_string()),
+    })
+}
+
+/// Splits `expr,X` and `expr,Y`
+fn split_index(text: &str) -> Option<(&str, char)> {
+    let items = split_list(text).ok()?;
+    if items.len() != 2 {
+        return None;
+    }
+    match items[1].to_ascii_uppercase().as_str() {
+        "X" => Some((items[0], 'X')),
+        "Y" => Some((items[0], 'Y')),
+        _ => None,
+    }
+}
+
+/// Splits on the commas that aren't in parentheses or quotes
+fn split_list(text: &str) -> Result<Vec<&str>, AsmErrorKind> {
+    let mut items = vec![];
+    let (mut depth, mut start) = (0i32, 0);
+    let mut quote = None;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                items.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(text[start..].trim());
+    if items.iter().any(|item| item.is_empty()) {
+        return syntax_error("Empty item in a list");
+    }
+    Ok(items)
+}
+
+/// Index of the parenthesis closing the one `text` starts with
+fn matching_paren(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, ';') => return &text[..i],
+            _ => {}
+        }
+    }
+    text
+}
+
+fn identifier_len(text: &str) -> usize {
+    let mut len = 0;
+    for (i, c) in text.char_indices() {
+        let valid = c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit());
+        if !valid {
+            break;
+        }
+        len = i + c.len_utf8();
+    }
+    len
+}
+
+/// Splits `label:` from the rest of the line
+fn split_label(text: &str) -> (Option<&str>, &str) {
+    let len = identifier_len(text);
+    if len > 0 && text[len..].starts_with(':') {
+        (Some(&text[..len]), text[len + 1..].trim())
+    } else {
+        (None, text)
+    }
+}
+
+/// Splits the first word from the rest of the line
+fn split_word(text: &str) -> (&str, &str) {
+    match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], text[i..].trim()),
+        None => (text, ""),
+    }
+}
+
+fn string_literal(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        Some(text.as_bytes()[1..text.len() - 1].to_vec())
+    } else {
+        None
+    }
+}
+/* #endregion */
+
+/* #region Expressions */
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Symbol(String),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, AsmErrorKind> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    // Collects the characters of a number in the given base
+    let number = |i: &mut usize, radix: u32| -> Result<Token, AsmErrorKind> {
+        let start = *i;
+        while *i < chars.len() && (chars[*i].is_digit(radix) || chars[*i] == '_') {
+            *i += 1;
+        }
+        let digits: String = chars[start..*i].iter().filter(|c| **c != '_').collect();
+        i64::from_str_radix(&digits, radix)
+            .map(Token::Number)
+            .map_err(|_| AsmErrorKind::Syntax(format!("Invalid number in \"{}\"", text)))
+    };
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            _ if c.is_whitespace() => i += 1,
+            '$' => {
+                i += 1;
+                tokens.push(number(&mut i, 16)?);
+            }
+            '%' if matches!(next, Some('0') | Some('1')) && expects_value(&tokens) => {
+                i += 1;
+                tokens.push(number(&mut i, 2)?);
+            }
+            '0' if next == Some('x') || next == Some('X') => {
+                i += 2;
+                tokens.push(number(&mut i, 16)?);
+            }
+            _ if c.is_ascii_digit() => tokens.push(number(&mut i, 10)?),
+            '\'' => match (chars.get(i + 1), chars.get(i + 2)) {
+                (Some(c), Some('\'')) => {
+                    tokens.push(Token::Number(*c as i64));
+                    i += 3;
+                }
+                _ => return syntax_error("Character literals look like 'c'"),
+            },
+            _ if c == '_' || c.is_ascii_alphabetic() => {
+                let start = i;
+                while i < chars.len() && (chars[i] == '_' || chars[i].is_ascii_alphanumeric()) {
+                    i += 1;
+                }
+                tokens.push(Token::Symbol(chars[start..i].iter().collect()));
+            }
+            '(' => {
+                tokens.push(Token::Open);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::Close);
+                i += 1;
+            }
+            _ => {
+                let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+                let op = match two.as_str() {
+                    "<<" => "<<",
+                    ">>" => ">>",
+                    _ => match c {
+                        '+' => "+",
+                        '-' => "-",
+                        '*' => "*",
+                        '/' => "/",
+                        '%' => "%",
+                        '&' => "&",
+                        '|' => "|",
+                        '^' => "^",
+                        '~' => "~",
+                        '<' => "<",
+                        '>' => ">",
+                        _ => return syntax_error(&format!("Unexpected '{}' in \"{}\"", c, text)),
+                    },
+                };
+                tokens.push(Token::Op(op));
+                i += op.len();
+            }
+        }
+    }
+    Ok(tokens)
+}
+/// Whether the next token starts a value, rather than being an operator
+fn expects_value(tokens: &[Token]) -> bool {
+    matches!(tokens.last(), None | Some(Token::Op(_)) | Some(Token::Open))
+}
+
+fn precedence(op: &str) -> Option<u8> {
+    match op {
+        "|" => Some(1),
+        "^" => Some(2),
+        "&" => Some(3),
+        "<<" | ">>" => Some(4),
+        "+" | "-" => Some(5),
+        "*" | "/" | "%" => Some(6),
+        _ => None,
+    }
+}
+
+/// Evaluates expressions. Values are `None` while they depend on unknown symbols
+struct Evaluator<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    symbols: &'a BTreeMap<String, i64>,
+    pc: i64,
+    /// Unknown symbols are errors instead of unknown values
+    strict: bool,
+}
+impl<'a> Evaluator<'a> {
+    fn eval(
+        text: &str,
+        symbols: &'a BTreeMap<String, i64>,
+        pc: i64,
+        strict: bool,
+    ) -> Result<Option<i64>, AsmErrorKind> {
+        let mut evaluator = Evaluator {
+            tokens: tokenize(text)?,
+            pos: 0,
+            symbols,
+            pc,
+            strict,
+        };
+        if evaluator.tokens.is_empty() {
+            return syntax_error("Missing expression");
+        }
+        let value = evaluator.binary(1)?;
+        if evaluator.pos != evaluator.tokens.len() {
+            return syntax_error(&format!("Unexpected text in \"{}\"", text));
+        }
+        Ok(value)
+    }
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+    fn binary(&mut self, min_precedence: u8) -> Result<Option<i64>, AsmErrorKind> {
+        let mut lhs = self.unary()?;
+        while let Some(Token::Op(op)) = self.tokens.get(self.pos).cloned() {
+            let prec = match precedence(op) {
+                Some(prec) if prec >= min_precedence => prec,
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.binary(prec + 1)?;
+            lhs = match (lhs, rhs) {
+                (Some(a), Some(b)) => Some(match op {
+                    "|" => a | b,
+                    "^" => a ^ b,
+                    "&" => a & b,
+                    "<<" => a.wrapping_shl(b as u32),
+                    ">>" => a.wrapping_shr(b as u32),
+                    "+" => a.wrapping_add(b),
+                    "-" => a.wrapping_sub(b),
+                    "*" => a.wrapping_mul(b),
+                    "/" | "%" if b == 0 => return Err(AsmErrorKind::DivisionByZero),
+                    "/" => a / b,
+                    _ => a % b,
+                }),
+                _ => None,
+            };
+        }
+        Ok(lhs)
+    }
+    fn unary(&mut self) -> Result<Option<i64>, AsmErrorKind> {
+        let value = match self.next() {
+            Some(Token::Number(n)) => Some(n),
+            Some(Token::Symbol(name)) => match self.symbols.get(&name) {
+                Some(value) => Some(*value),
+                None if self.strict => return Err(AsmErrorKind::UnknownSymbol(name)),
+                None => None,
+            },
+            Some(Token::Open) => {
+                let value = self.binary(1)?;
+                if self.next() != Some(Token::Close) {
+                    return syntax_error("Missing ')'");
+                }
+                value
+            }
+            Some(Token::Op("*")) => Some(self.pc),
+            Some(Token::Op(op)) => {
+                let value = self.unary()?;
+                match op {
+                    "-" => value.map(|n| -n),
+                    "+" => value,
+                    "~" => value.map(|n| !n),
+                    "<" => value.map(|n| n & 0xFF),
+                    ">" => value.map(|n| (n >> 8) & 0xFF),
+                    _ => return syntax_error(&format!("'{}' needs a value on its left", op)),
+                }
+            }
+            Some(Token::Close) => return syntax_error("Unexpected ')'"),
+            None => return syntax_error("Expression ends too soon"),
+        };
+        Ok(value)
+    }
+}
+/* #endregion */
+
+/* #region Passes */
+#[derive(Default)]
+struct Assembler {
+    symbols: BTreeMap<String, i64>,
+    /// Addressing mode chosen for each statement on the first pass
+    modes: Vec<Option<AddressingMode>>,
+}
+impl Assembler {
+    fn run(mut self, statements: &[Statement]) -> Result<Assembly, AsmError> {
+        self.first_pass(statements)?;
+        self.second_pass(statements)
+    }
+
+    /// Defines every symbol and sizes every statement
+    fn first_pass(&mut self, statements: &[Statement]) -> Result<(), AsmError> {
+        let mut pc = DEFAULT_ORIGIN as i64;
+        for statement in statements {
+            let at = |kind| statement.error(kind);
+            if let Some(label) = &statement.label {
+                self.define(label, pc).map_err(at)?;
+            }
+            let mut mode = None;
+            match &statement.kind {
+                Kind::Empty | Kind::Include(_) => {}
+                Kind::Org(expr) => pc = self.known_address(expr, pc).map_err(at)?,
+                Kind::Constant(name, expr) => {
+                    let value = Evaluator::eval(expr, &self.symbols, pc, true).map_err(at)?;
+                    self.define(name, value.unwrap_or(0)).map_err(at)?;
+                }
+                Kind::Byte(items) => {
+                    pc += items
+                        .iter()
+                        .map(|item| match item {
+                            Data::Text(text) => text.len() as i64,
+                            Data::Expr(_) => 1,
+                        })
+                        .sum::<i64>();
+                }
+                Kind::Word(items) => pc += 2 * items.len() as i64,
+                Kind::Instruction(opcode, syntax) => {
+                    let chosen = self.choose_mode(*opcode, syntax, pc).map_err(at)?;
+                    pc += get_size(chosen) as i64;
+                    mode = Some(chosen);
+                }
+            }
+            if pc > 0x10000 {
+                return Err(at(AsmErrorKind::AddressOverflow));
+            }
+            self.modes.push(mode);
+        }
+        Ok(())
+    }
+
+    /// Emits the bytes, now that every symbol is known
+    fn second_pass(&self, statements: &[Statement]) -> Result<Assembly, AsmError> {
+        let mut assembly = Assembly::default();
+        let mut pc = DEFAULT_ORIGIN as i64;
+        for (statement, mode) in statements.iter().zip(self.modes.iter()) {
+            let at = |kind| statement.error(kind);
+            let mut bytes = vec![];
+            match &statement.kind {
+                Kind::Empty | Kind::Include(_) | Kind::Constant(..) => {}
+                Kind::Org(expr) => pc = self.known_address(expr, pc).map_err(at)?,
+                Kind::Byte(items) => {
+                    for item in items {
+                        match item {
+                            Data::Text(text) => bytes.extend_from_slice(text),
+                            Data::Expr(expr) => bytes.push(self.byte(expr, pc).map_err(at)?),
+                        }
+                    }
+                }
+                Kind::Word(items) => {
+                    for expr in items {
+                        let word = self.word(expr, pc).map_err(at)?;
+                        bytes.extend_from_slice(&[word as u8, (word >> 8) as u8]);
+                    }
+                }
+                Kind::Instruction(opcode, syntax) => {
+                    let mode = mode.expect("The first pass picks a mode for every instruction");
+                    bytes = self.encode(*opcode, syntax, mode, pc).map_err(at)?;
+                    if assembly.image.entry.is_none() {
+                        assembly.image.entry = Some(Address(pc as usize));
+                    }
+                }
+            }
+            if bytes.is_empty() {
+                continue;
+            }
+            assembly.lines.push(SourceLine {
+                file: statement.file.to_path_buf(),
+                line: statement.line,
+                address: Address(pc as usize),
+                size: bytes.len(),
+            });
+            // Contiguous statements share a chunk
+            match assembly.image.chunks.last_mut() {
+                Some((start, data)) if (**start + data.len()) as i64 == pc => {
+                    data.extend_from_slice(&bytes)
+                }
+                _ => assembly
+                    .image
+                    .chunks
+                    .push((Address(pc as usize), bytes.clone())),
+            }
+            pc += bytes.len() as i64;
+        }
+        assembly.symbols = self
+            .symbols
+            .iter()
+            .map(|(name, value)| (name.clone(), *value as u16))
+            .collect();
+        Ok(assembly)
+    }
+
+    fn define(&mut self, name: &str, value: i64) -> Result<(), AsmErrorKind> {
+        if self.symbols.insert(name.to_string(), value).is_some() {
+            return Err(AsmErrorKind::Redefined(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Evaluates an expression that has to be known on the first pass
+    fn known_address(&self, expr: &str, pc: i64) -> Result<i64, AsmErrorKind> {
+        let value = Evaluator::eval(expr, &self.symbols, pc, true)?.unwrap_or(0);
+        if !(0..=0xFFFF).contains(&value) {
+            return Err(AsmErrorKind::OutOfRange(value));
+        }
+        Ok(value)
+    }
+    fn value(&self, expr: &str, pc: i64) -> Result<i64, AsmErrorKind> {
+        Ok(Evaluator::eval(expr, &self.symbols, pc, true)?.unwrap_or(0))
+    }
+    /// Negative values down to -128 are stored in two's complement
+    fn byte(&self, expr: &str, pc: i64) -> Result<u8, AsmErrorKind> {
+        match self.value(expr, pc)? {
+            value if (-0x80..=0xFF).contains(&value) => Ok(value as u8),
+            value => Err(AsmErrorKind::OutOfRange(value)),
+        }
+    }
+    fn word(&self, expr: &str, pc: i64) -> Result<u16, AsmErrorKind> {
+        match self.value(expr, pc)? {
+            value if (-0x8000..=0xFFFF).contains(&value) => Ok(value as u16),
+            value => Err(AsmErrorKind::OutOfRange(value)),
+        }
+    }
+
+    /// Zero page when the operand is known to fit and the instruction has it.
+    /// Absolute otherwise, which is what forward references get
+    fn choose_mode(
+        &self,
+        opcode: OpcodeType,
+        syntax: &Syntax,
+        pc: i64,
+    ) -> Result<AddressingMode, AsmErrorKind> {
+        use AddressingMode::*;
+        let has = |mode| get_code(opcode, mode).is_some();
+        let fits_zero_page = |expr: &str| -> Result<bool, AsmErrorKind> {
+            let value = Evaluator::eval(expr, &self.symbols, pc, false)?;
+            Ok(matches!(value, Some(value) if (0..=0xFF).contains(&value)))
+        };
+        let pick = |expr: &str, zero_page, absolute| -> Result<AddressingMode, AsmErrorKind> {
+            Ok(match (has(zero_page), has(absolute)) {
+                (true, true) if fits_zero_page(expr)? => zero_page,
+                (_, true) => absolute,
+                _ => zero_page,
+            })
+        };
+        let (mode, name) = match syntax {
+            Syntax::None if has(IMPL) => (IMPL, "implied"),
+            Syntax::None => (A, "implied"),
+            Syntax::Accumulator => (A, "accumulator"),
+            Syntax::Immediate(_) => (IMM, "immediate"),
+            Syntax::Direct(_) if opcode.is_branch_op() => (REL, "relative"),
+            Syntax::Direct(expr) => (pick(expr, ZPG, ABS)?, "absolute"),
+            Syntax::IndexedX(expr) => (pick(expr, ZPGX, ABSX)?, "X indexed"),
+            Syntax::IndexedY(expr) => (pick(expr, ZPGY, ABSY)?, "Y indexed"),
+            Syntax::Indirect(_) => (IND, "indirect"),
+            Syntax::IndirectX(_) => (INDX, "(zp,X)"),
+            Syntax::IndirectY(_) => (INDY, "(zp),Y"),
+        };
+        if !has(mode) {
+            return Err(AsmErrorKind::InvalidMode {
+                mnemonic: opcode.into(),
+                mode: name,
+            });
+        }
+        Ok(mode)
+    }
+
+    fn encode(
+        &self,
+        opcode: OpcodeType,
+        syntax: &Syntax,
+        mode: AddressingMode,
+        pc: i64,
+    ) -> Result<Vec<u8>, AsmErrorKind> {
+        use AddressingMode::*;
+        let code = get_code(opcode, mode).expect("The first pass checks the mode exists");
+        let expr = match syntax {
+            Syntax::None | Syntax::Accumulator => return Ok(vec![code]),
+            Syntax::Immediate(expr)
+            | Syntax::Direct(expr)
+            | Syntax::IndexedX(expr)
+            | Syntax::IndexedY(expr)
+            | Syntax::Indirect(expr)
+            | Syntax::IndirectX(expr)
+            | Syntax::IndirectY(expr) => expr,
+        };
+        Ok(match mode {
+            IMM => vec![code, self.byte(expr, pc)?],
+            REL => {
+                let offset = self.value(expr, pc)? - (pc + 2);
+                if !(-0x80..=0x7F).contains(&offset) {
+                    return Err(AsmErrorKind::BranchTooFar(offset));
+                }
+                vec![code, offset as u8]
+            }
+            ZPG | ZPGX | ZPGY | INDX | INDY => match self.value(expr, pc)? {
+                value if (0..=0xFF).contains(&value) => vec![code, value as u8],
+                value => return Err(AsmErrorKind::OutOfRange(value)),
+            },
+            _ => match self.value(expr, pc)? {
+                value if (0..=0xFFFF).contains(&value) => {
+                    vec![code, value as u8, (value >> 8) as u8]
+                }
+                value => return Err(AsmErrorKind::OutOfRange(value)),
+            },
+        })
+    }
+}
+/* #endregion */
+
+mod test {
+    #[test]
+    fn test_assemble() {
+        use super::assemble;
+        use crate::emulator::addressing_modes::Address;
+        use crate::emulator::error::AsmErrorKind;
+        let source = "
+            SCREEN = $0200
+                    .org $0600
+            start:  LDX #0          ; X indexes the colors
+            loop:   LDA colors,X
+                    STA SCREEN,X
+                    STA (ptr),Y
+                    JMP (ptr)
+                    ASL
+                    ROR A
+                    INX
+                    CPX #end - colors
+                    BNE loop
+                    LDA #<start
+                    STX $10,Y
+            ptr     = $FE
+                    LDA ptr
+                    BRK
+            colors: .byte 1, %10, 'A', \"hi;\"
+            end:    .word start, * + 1
+        ";
+        let assembly = assemble(source).unwrap();
+        let expected: Vec<u8> = vec![
+            0xA2, 0x00, // LDX #0
+            0xBD, 0x1B, 0x06, // LDA colors,X (forward, so absolute)
+            0x9D, 0x00, 0x02, // STA SCREEN,X
+            0x91, 0xFE, // STA (ptr),Y
+            0x6C, 0xFE, 0x00, // JMP (ptr)
+            0x0A, // ASL
+            0x6A, // ROR A
+            0xE8, // INX
+            0xE0, 0x06, // CPX #6
+            0xD0, 0xEE, // BNE loop
+            0xA9, 0x00, // LDA #<start
+            0x96, 0x10, // STX $10,Y
+            0xA5, 0xFE, // LDA ptr (known, so zero page)
+            0x00, // BRK
+            0x01, 0x02, 0x41, 0x68, 0x69, 0x3B, // .byte
+            0x00, 0x06, 0x22, 0x06, // .word
+        ];
+        assert_eq!(assembly.image.chunks, vec![(Address(0x0600), expected)]);
+        assert_eq!(assembly.image.entry, Some(Address(0x0600)));
+        assert_eq!(assembly.symbols["loop"], 0x0602);
+        assert_eq!(assembly.lines[1].line, 5);
+
+        let error = assemble("  NOP\n  LDA (1,Y)\n").unwrap_err();
+        assert_eq!(error.line, 2);
+        let error = assemble("  BNE far\n  .org $0700\nfar: NOP\n").unwrap_err();
+        match error.kind {
+            AsmErrorKind::BranchTooFar(_) => assert_eq!(error.line, 1),
+            other => panic!("Expected a branch error, got {:?}", other),
+        }
+    }
+}
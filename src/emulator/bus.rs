@@ -0,0 +1,392 @@
+use super::addressing_modes::Address;
+use super::error::StateError;
+
+/// Everything the CPU reads or writes goes through a `Bus`.
+/// Devices see the accesses exactly as the CPU does them, dummy reads included
+pub trait Bus: Send {
+    fn read(&mut self, addr: Address) -> u8;
+    fn write(&mut self, addr: Address, value: u8);
+    /// A read the CPU does while computing an address, whose value is thrown away.
+    /// Devices with read side effects see it as a normal read unless they override this
+    fn dummy_read(&mut self, addr: Address) {
+        self.read(addr);
+    }
+    /// Reads without side effects, for debuggers and the UI
+    fn peek(&self, addr: Address) -> u8;
+    /// Copies `data` starting at `start`, bypassing any write protection
+    fn load(&mut self, start: Address, data: &[u8]) {
+        for (i, value) in data.iter().enumerate() {
+            self.write(start.wrapping_add(i), *value);
+        }
+    }
+    /// Called when the system restarts, to go back to the power-on state
+    fn reset(&mut self) {}
+    /// What the device needs to carry on where it left off, for save states.
+    /// Devices without state of their own, like ROM, save nothing
+    fn save_state(&self) -> Vec<u8> {
+        vec![]
+    }
+    /// Goes back to what `save_state` returned
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        if state.is_empty() {
+            Ok(())
+        } else {
+            Err(StateError::Mismatch)
+        }
+    }
+    /// `peek` from `start` up to `end`, which is left out unlike in the crate's other
+    /// ranges: page $02 is `peek_range(Address(0x0200), Address(0x0300))`
+    fn peek_range(&self, start: Address, end: Address) -> Vec<u8> {
+        (*start..*end)
+            .map(|addr| self.peek(Address(addr)))
+            .collect()
+    }
+}
+
+/* #region Memory */
+/// A block of RAM or ROM meant to be mapped into a window of a `MemoryMap`
+pub struct Memory {
+    data: Vec<u8>,
+    read_only: bool,
+}
+impl Memory {
+    pub fn ram(size: usize) -> Self {
+        Self {
+            data: vec![0x00; size],
+            read_only: false,
+        }
+    }
+    pub fn rom(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            read_only: true,
+        }
+    }
+}
+impl Bus for Memory {
+    fn read(&mut self, addr: Address) -> u8 {
+        self.peek(addr)
+    }
+    fn write(&mut self, addr: Address, value: u8) {
+        if !self.read_only {
+            if let Some(byte) = self.data.get_mut(*addr) {
+                *byte = value;
+            }
+        }
+    }
+    fn peek(&self, addr: Address) -> u8 {
+        self.data.get(*addr).copied().unwrap_or(0x00)
+    }
+    fn load(&mut self, start: Address, data: &[u8]) {
+        for (i, value) in data.iter().enumerate() {
+            if let Some(byte) = self.data.get_mut(*start + i) {
+                *byte = *value;
+            }
+        }
+    }
+    fn save_state(&self) -> Vec<u8> {
+        if self.read_only {
+            vec![]
+        } else {
+            self.data.clone()
+        }
+    }
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        match self.read_only {
+            true if state.is_empty() => Ok(()),
+            false if state.len() == self.data.len() => {
+                self.data.copy_from_slice(state);
+                Ok(())
+            }
+            _ => Err(StateError::Mismatch),
+        }
+    }
+    fn reset(&mut self) {
+        // ROM keeps its contents
+        if !self.read_only {
+            self.data.iter_mut().for_each(|byte| *byte = 0x00);
+        }
+    }
+}
+
+/// Reads as $00 and ignores writes. What a `MemoryMap` answers with where nothing is mapped
+pub struct OpenBus;
+impl Bus for OpenBus {
+    fn read(&mut self, _: Address) -> u8 {
+        0x00
+    }
+    fn write(&mut self, _: Address, _: u8) {}
+    fn peek(&self, _: Address) -> u8 {
+        0x00
+    }
+}
+/* #endregion */
+
+/* #region Devices */
+/// Every read gives a new pseudo-random byte, like $FE on easy6502.
+/// Always starts from the same seed, so runs can be replayed
+pub struct Random(u32);
+impl Random {
+    const SEED: u32 = 0x6502_6502;
+    pub fn new() -> Self {
+        Random(Self::SEED)
+    }
+}
+impl std::default::Default for Random {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Bus for Random {
+    fn read(&mut self, _: Address) -> u8 {
+        // xorshift32
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as u8
+    }
+    fn write(&mut self, _: Address, _: u8) {}
+    fn peek(&self, _: Address) -> u8 {
+        self.0 as u8
+    }
+    fn reset(&mut self) {
+        self.0 = Self::SEED;
+    }
+    fn save_state(&self) -> Vec<u8> {
+        self.0.to_le_bytes().to_vec()
+    }
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut seed = [0; 4];
+        if state.len() != seed.len() {
+            return Err(StateError::Mismatch);
+        }
+        seed.copy_from_slice(state);
+        self.0 = u32::from_le_bytes(seed);
+        Ok(())
+    }
+}
+/* #endregion */
+
+/* #region MemoryMap */
+struct Mapping {
+    start: Address,
+    end: Address,
+    device: Box<dyn Bus>,
+}
+impl Mapping {
+    fn contains(&self, addr: Address) -> bool {
+        self.start <= addr && addr <= self.end
+    }
+    fn offset(&self, addr: Address) -> Address {
+        Address(*addr - *self.start)
+    }
+}
+struct Mirror {
+    start: Address,
+    end: Address,
+    target: Address,
+    size: usize,
+}
+
+/// A bus made of devices mapped into address ranges.
+/// Accesses that don't hit any device go to `fallback`
+pub struct MemoryMap {
+    fallback: Box<dyn Bus>,
+    mappings: Vec<Mapping>,
+    mirrors: Vec<Mirror>,
+    protected: Vec<(Address, Address)>,
+}
+impl MemoryMap {
+    pub fn new(fallback: Box<dyn Bus>) -> Self {
+        Self {
+            fallback,
+            mappings: vec![],
+            mirrors: vec![],
+            protected: vec![],
+        }
+    }
+    /// Maps `device` to `start..=end`. The device sees addresses relative to `start`.
+    /// When ranges overlap, the last device mapped wins
+    pub fn map(&mut self, start: Address, end: Address, device: Box<dyn Bus>) {
+        self.mappings.push(Mapping { start, end, device });
+    }
+    /// Makes `start..=end` an alias of the `size` bytes at `target`, repeated as needed
+    pub fn mirror(&mut self, start: Address, end: Address, target: Address, size: usize) {
+        self.mirrors.push(Mirror {
+            start,
+            end,
+            target,
+            size: size.max(1),
+        });
+    }
+    /// Drops CPU writes to `start..=end`. Loading a program still works
+    pub fn protect(&mut self, start: Address, end: Address) {
+        self.protected.push((start, end));
+    }
+    /// Where an access to `addr` really goes once mirrors are applied
+    fn translate(&self, addr: Address) -> Address {
+        match self
+            .mirrors
+            .iter()
+            .find(|m| m.start <= addr && addr <= m.end)
+        {
+            Some(m) => m.target.wrapping_add((*addr - *m.start) % m.size),
+            None => addr,
+        }
+    }
+    fn is_protected(&self, addr: Address) -> bool {
+        self.protected
+            .iter()
+            .any(|&(start, end)| start <= addr && addr <= end)
+    }
+    fn find(&self, addr: Address) -> Option<usize> {
+        self.mappings.iter().rposition(|m| m.contains(addr))
+    }
+    /// The fallback, then the mapped devices in order
+    fn devices(&self) -> impl Iterator<Item = &dyn Bus> {
+        std::iter::once(&*self.fallback).chain(self.mappings.iter().map(|m| &*m.device))
+    }
+}
+impl Bus for MemoryMap {
+    fn read(&mut self, addr: Address) -> u8 {
+        let addr = self.translate(addr);
+        match self.find(addr) {
+            Some(i) => {
+                let mapping = &mut self.mappings[i];
+                let offset = mapping.offset(addr);
+                mapping.device.read(offset)
+            }
+            None => self.fallback.read(addr),
+        }
+    }
+    fn write(&mut self, addr: Address, value: u8) {
+        let addr = self.translate(addr);
+        if self.is_protected(addr) {
+            return;
+        }
+        match self.find(addr) {
+            Some(i) => {
+                let mapping = &mut self.mappings[i];
+                let offset = mapping.offset(addr);
+                mapping.device.write(offset, value)
+            }
+            None => self.fallback.write(addr, value),
+        }
+    }
+    fn dummy_read(&mut self, addr: Address) {
+        let addr = self.translate(addr);
+        match self.find(addr) {
+            Some(i) => {
+                let mapping = &mut self.mappings[i];
+                let offset = mapping.offset(addr);
+                mapping.device.dummy_read(offset)
+            }
+            None => self.fallback.dummy_read(addr),
+        }
+    }
+    fn peek(&self, addr: Address) -> u8 {
+        let addr = self.translate(addr);
+        match self.find(addr) {
+            Some(i) => {
+                let mapping = &self.mappings[i];
+                mapping.device.peek(mapping.offset(addr))
+            }
+            None => self.fallback.peek(addr),
+        }
+    }
+    fn load(&mut self, start: Address, data: &[u8]) {
+        // Byte by byte, so each one lands in whatever is mapped there
+        for (i, value) in data.iter().enumerate() {
+            let addr = self.translate(start.wrapping_add(i));
+            match self.find(addr) {
+                Some(m) => {
+                    let mapping = &mut self.mappings[m];
+                    let offset = mapping.offset(addr);
+                    mapping.device.load(offset, &[*value])
+                }
+                None => self.fallback.load(addr, &[*value]),
+            }
+        }
+    }
+    fn reset(&mut self) {
+        self.fallback.reset();
+        for mapping in self.mappings.iter_mut() {
+            mapping.device.reset();
+        }
+    }
+    /// The fallback's state and then every device's, each after its length
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![];
+        for device in self.devices() {
+            let device_state = device.save_state();
+            state.extend_from_slice(&(device_state.len() as u32).to_le_bytes());
+            state.extend_from_slice(&device_state);
+        }
+        state
+    }
+    fn load_state(&mut self, mut state: &[u8]) -> Result<(), StateError> {
+        // The file as a whole was already checked, so anything that doesn't split up
+        // into blocks comes from a different bus
+        let mut states = vec![];
+        while !state.is_empty() {
+            if state.len() < 4 {
+                return Err(StateError::Mismatch);
+            }
+            let mut len = [0u8; 4];
+            len.copy_from_slice(&state[..4]);
+            let len = u32::from_le_bytes(len) as usize;
+            if state.len() < 4 + len {
+                return Err(StateError::Mismatch);
+            }
+            states.push(&state[4..4 + len]);
+            state = &state[4 + len..];
+        }
+        // Checked before changing anything, so a bad state leaves the machine as it was
+        let fits = states.len() == self.mappings.len() + 1
+            && self
+                .devices()
+                .zip(&states)
+                .all(|(device, state)| device.save_state().len() == state.len());
+        if !fits {
+            return Err(StateError::Mismatch);
+        }
+        self.fallback.load_state(states[0])?;
+        for (mapping, state) in self.mappings.iter_mut().zip(&states[1..]) {
+            mapping.device.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+/* #endregion */
+
+mod test {
+    #[test]
+    fn test_memory_map() {
+        use super::{Bus, MemoryMap};
+        use crate::emulator::addressing_modes::Address;
+        use crate::emulator::components::Ram;
+        // Counts reads, so dummy reads can be told apart
+        struct Counter(u8);
+        impl Bus for Counter {
+            fn read(&mut self, _: Address) -> u8 {
+                self.0 += 1;
+                self.0
+            }
+            fn write(&mut self, _: Address, value: u8) {
+                self.0 = value;
+            }
+            fn dummy_read(&mut self, _: Address) {}
+            fn peek(&self, _: Address) -> u8 {
+                self.0
+            }
+        }
+        let mut map = MemoryMap::new(Box::new(Ram::new()));
+        map.map(Address(0x8000), Address(0x8FFF), Box::new(Counter(0)));
+        map.write(Address(0x8010), 0x40);
+        map.dummy_read(Address(0x8000));
+        assert_eq!(map.read(Address(0x8000)), 0x41);
+        assert_eq!(map.peek(Address(0x8000)), 0x41);
+        map.write(Address(0x7FFF), 0x12);
+        assert_eq!(map.read(Address(0x7FFF)), 0x12);
+    }
+}
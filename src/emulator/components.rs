@@ -1,10 +1,51 @@
 use super::addressing_modes::Address;
+use super::bus::Bus;
+use super::error::StateError;
 
 /* #region Ram */
+/// Flat 64 KiB of RAM, the default bus
 pub struct Ram(pub [u8; 0x10000]);
 impl Ram {
-    pub fn load(&mut self, code: [u8; 0x10000]) {
-        self.0 = code;
+    pub fn new() -> Self {
+        Ram([0x00; 0x10000])
+    }
+}
+impl std::default::Default for Ram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Bus for Ram {
+    fn read(&mut self, addr: Address) -> u8 {
+        self[addr]
+    }
+    fn write(&mut self, addr: Address, value: u8) {
+        self[addr] = value;
+    }
+    fn peek(&self, addr: Address) -> u8 {
+        self[addr]
+    }
+    fn load(&mut self, start: Address, data: &[u8]) {
+        let start: usize = start.into();
+        let len = data.len().min(0x10000 - start);
+        self.0[start..start + len].copy_from_slice(&data[..len]);
+        // Whatever doesn't fit wraps around to $0000
+        if len < data.len() {
+            self.load(Address(0), &data[len..]);
+        }
+    }
+    fn reset(&mut self) {
+        self.0 = [0x00; 0x10000];
+    }
+    fn save_state(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        if state.len() != self.0.len() {
+            return Err(StateError::Mismatch);
+        }
+        self.0.copy_from_slice(state);
+        Ok(())
     }
 }
 impl std::ops::Index<Address> for Ram {
@@ -46,9 +87,13 @@ pub struct Registers {
     pub X: u8,
     pub Y: u8,
     pub PC: Address,
+    pub S: u8,
     pub flags: u8,
 }
 impl Registers {
+    pub fn stack_addr(&self) -> Address {
+        Address(0x0100 | self.S as usize)
+    }
     pub fn test(&self, flag: Flags) -> bool {
         (self.flags & flag as u8) != 0
     }
@@ -60,21 +105,141 @@ impl Registers {
         }
     }
     pub fn add_a(&mut self, n: u8) {
-        let n = n as u16;
-        let a = self.A as u16;
-        let res = n + a;
-        if res > 0xFF {
-            self.set_flag(Flags::Carry, true);
-        } else {
-            self.set_flag(Flags::Carry, false);
-        }
+        let carry = self.test(Flags::Carry) as u16;
+        let res = self.A as u16 + n as u16 + carry;
+        self.set_flag(Flags::Carry, res > 0xFF);
+        // Overflow if both operands have the same sign and the result doesn't
+        let overflow = (self.A ^ res as u8) & (n ^ res as u8) & 0x80 != 0;
+        self.set_flag(Flags::Overflow, overflow);
         self.set_a((res & 0xFF) as u8);
     }
-    pub fn set_a(&mut self, value: u8) {
-        self.set_flag(Flags::Zero, value == 0x00); // Set Zero if A is zero
+    pub fn sub_a(&mut self, n: u8) {
+        // A - M - !C == A + !M + C
+        self.add_a(!n);
+    }
+    /// ADC with the D flag set, as done by the NMOS 6502.
+    /// C is the decimal carry, but Z comes from the binary sum and
+    /// N and V from the sum before the high nibble gets adjusted
+    pub fn add_a_decimal(&mut self, n: u8) {
+        let carry = self.test(Flags::Carry) as i16;
+        let (a, b) = (self.A as i16, n as i16);
+        let binary = (a + b + carry) as u8;
+        let mut lo = (a & 0x0F) + (b & 0x0F) + carry;
+        if lo >= 0x0A {
+            lo = ((lo + 0x06) & 0x0F) + 0x10;
+        }
+        let mut res = (a & 0xF0) + (b & 0xF0) + lo;
+        // N and V see the intermediate result as a signed number
+        let signed = ((a & 0xF0) as u8 as i8 as i16) + ((b & 0xF0) as u8 as i8 as i16) + lo;
+        self.set_flag(Flags::Negative, res & 0x80 != 0);
+        self.set_flag(Flags::Overflow, !(-128..=127).contains(&signed));
+        self.set_flag(Flags::Zero, binary == 0);
+        if res >= 0xA0 {
+            res += 0x60;
+        }
+        self.set_flag(Flags::Carry, res >= 0x100);
+        self.A = res as u8;
+    }
+    /// SBC with the D flag set, as done by the NMOS 6502.
+    /// Every flag is set exactly like the binary subtraction would
+    pub fn sub_a_decimal(&mut self, n: u8) {
+        let borrow = !self.test(Flags::Carry) as i16;
+        let (a, b) = (self.A as i16, n as i16);
+        let mut lo = (a & 0x0F) - (b & 0x0F) - borrow;
+        if lo < 0 {
+            lo = ((lo - 0x06) & 0x0F) - 0x10;
+        }
+        let mut res = (a & 0xF0) - (b & 0xF0) + lo;
+        if res < 0 {
+            res -= 0x60;
+        }
+        self.sub_a(n);
+        self.A = res as u8;
+    }
+    pub fn compare(&mut self, reg: u8, value: u8) {
+        self.set_flag(Flags::Carry, reg >= value);
+        self.set_nz(reg.wrapping_sub(value));
+    }
+    /// The undocumented ARR: AND, then ROR A with C and V taken from bits 6 and 5
+    pub fn arr(&mut self, n: u8) {
+        let value = self.A & n;
+        let res = value >> 1 | (self.test(Flags::Carry) as u8) << 7;
+        self.set_a(res);
+        self.set_flag(Flags::Carry, res & 0x40 != 0);
+        self.set_flag(Flags::Overflow, (res ^ res << 1) & 0x40 != 0);
+    }
+    /// ARR with the D flag set. N, Z and V come from the rotated value,
+    /// then each nibble gets a decimal fix-up based on the value before the rotation
+    pub fn arr_decimal(&mut self, n: u8) {
+        let value = self.A & n;
+        let mut res = value >> 1 | (self.test(Flags::Carry) as u8) << 7;
+        self.set_nz(res);
+        self.set_flag(Flags::Overflow, (value ^ res) & 0x40 != 0);
+        if (value & 0x0F) + (value & 0x01) > 0x05 {
+            res = (res & 0xF0) | (res.wrapping_add(0x06) & 0x0F);
+        }
+        let fix_hi = (value as u16 & 0xF0) + (value as u16 & 0x10) > 0x50;
+        if fix_hi {
+            res = res.wrapping_add(0x60);
+        }
+        self.set_flag(Flags::Carry, fix_hi);
+        self.A = res;
+    }
+    /* #region Read-modify-write */
+    // These return the result and set the flags, the caller stores it
+    pub fn inc(&mut self, value: u8) -> u8 {
+        let value = value.wrapping_add(1);
+        self.set_nz(value);
+        value
+    }
+    pub fn dec(&mut self, value: u8) -> u8 {
+        let value = value.wrapping_sub(1);
+        self.set_nz(value);
+        value
+    }
+    pub fn asl(&mut self, value: u8) -> u8 {
+        self.set_flag(Flags::Carry, value & 0x80 != 0);
+        let value = value << 1;
+        self.set_nz(value);
+        value
+    }
+    pub fn lsr(&mut self, value: u8) -> u8 {
+        self.set_flag(Flags::Carry, value & 0x01 != 0);
+        let value = value >> 1;
+        self.set_nz(value);
+        value
+    }
+    pub fn rol(&mut self, value: u8) -> u8 {
+        let carry_in = self.test(Flags::Carry) as u8;
+        self.set_flag(Flags::Carry, value & 0x80 != 0);
+        let value = value << 1 | carry_in;
+        self.set_nz(value);
+        value
+    }
+    pub fn ror(&mut self, value: u8) -> u8 {
+        let carry_in = self.test(Flags::Carry) as u8;
+        self.set_flag(Flags::Carry, value & 0x01 != 0);
+        let value = value >> 1 | carry_in << 7;
+        self.set_nz(value);
+        value
+    }
+    /* #endregion */
+    pub fn set_nz(&mut self, value: u8) {
+        self.set_flag(Flags::Zero, value == 0x00); // Set Zero if value is zero
         self.set_flag(Flags::Negative, value & 0x80 != 0); // Test sign bit
+    }
+    pub fn set_a(&mut self, value: u8) {
+        self.set_nz(value);
         self.A = value;
     }
+    pub fn set_x(&mut self, value: u8) {
+        self.set_nz(value);
+        self.X = value;
+    }
+    pub fn set_y(&mut self, value: u8) {
+        self.set_nz(value);
+        self.Y = value;
+    }
 }
 impl std::default::Default for Registers {
     fn default() -> Self {
@@ -82,7 +247,8 @@ impl std::default::Default for Registers {
             A: 0x00,
             X: 0x00,
             Y: 0x00,
-            PC: 0x0000usize.into(),
+            PC: Address(0x0000),
+            S: 0x00,
             flags: 0b_0010_0000,
         }
     }
@@ -91,8 +257,8 @@ impl std::fmt::Debug for Registers {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         write!(
             f,
-            "Registers: \n PC: {:04X}\n A: {:02X} X: {:02X} Y: {:02X}\nNV-BDIZC\n{:08b}",
-            *self.PC, self.A, self.X, self.Y, self.flags
+            "Registers: \n PC: {:04X} S: {:02X}\n A: {:02X} X: {:02X} Y: {:02X}\nNV-BDIZC\n{:08b}",
+            *self.PC, self.S, self.A, self.X, self.Y, self.flags
         )
     }
 }
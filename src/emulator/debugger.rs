@@ -0,0 +1,308 @@
+use super::addressing_modes::Address;
+use super::components::Registers;
+use super::opcodes::{from_code, OpcodeType};
+use super::system::System;
+use std::fmt::{Display, Error as FmtError, Formatter};
+use std::str::FromStr;
+
+/* #region Watchpoints */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Reads and writes
+    Any,
+}
+
+/// Stops when the CPU reads or writes data in `start..=end`.
+/// Instruction fetches and dummy reads don't count
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub start: Address,
+    pub end: Address,
+    pub access: Access,
+}
+impl Watchpoint {
+    /// `access` is `Read` or `Write`, the kind of access the CPU did
+    pub fn matches(&self, addr: Address, access: Access) -> bool {
+        self.start <= addr
+            && addr <= self.end
+            && (self.access == Access::Any || self.access == access)
+    }
+}
+impl FromStr for Watchpoint {
+    type Err = String;
+    /// `read $10`, `write $0200-$02FF` or `access $4000-$4017`
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut words = text.split_whitespace();
+        let access = match words.next() {
+            Some("read") => Access::Read,
+            Some("write") => Access::Write,
+            Some("access") => Access::Any,
+            _ => return Err("Watchpoints start with read, write or access".to_string()),
+        };
+        let range = words.next().ok_or("Missing the watched address")?;
+        let (start, end) = match range.find('-') {
+            Some(i) => (address(&range[..i])?, address(&range[i + 1..])?),
+            None => (address(range)?, address(range)?),
+        };
+        if words.next().is_some() || end < start {
+            return Err(format!("Invalid watchpoint \"{}\"", text));
+        }
+        Ok(Self { start, end, access })
+    }
+}
+
+/// The access that triggered a watchpoint
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WatchHit {
+    pub addr: Address,
+    pub access: Access,
+    pub value: u8,
+}
+/* #endregion */
+
+/* #region Breakpoints */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    S,
+    P,
+    PC,
+}
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Compares a register with a value, like `A == $10`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: usize,
+}
+impl Condition {
+    pub fn holds(&self, registers: &Registers) -> bool {
+        let register = match self.register {
+            Register::A => registers.A as usize,
+            Register::X => registers.X as usize,
+            Register::Y => registers.Y as usize,
+            Register::S => registers.S as usize,
+            Register::P => registers.flags as usize,
+            Register::PC => *registers.PC,
+        };
+        match self.comparison {
+            Comparison::Eq => register == self.value,
+            Comparison::Ne => register != self.value,
+            Comparison::Lt => register < self.value,
+            Comparison::Le => register <= self.value,
+            Comparison::Gt => register > self.value,
+            Comparison::Ge => register >= self.value,
+        }
+    }
+}
+impl FromStr for Condition {
+    type Err = String;
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        if words.len() != 3 {
+            return Err(format!(
+                "Conditions look like \"A == $10\", got \"{}\"",
+                text
+            ));
+        }
+        let register = match words[0].to_ascii_uppercase().as_str() {
+            "A" => Register::A,
+            "X" => Register::X,
+            "Y" => Register::Y,
+            "S" | "SP" => Register::S,
+            "P" => Register::P,
+            "PC" => Register::PC,
+            other => return Err(format!("Unknown register {}", other)),
+        };
+        let comparison = match words[1] {
+            "==" => Comparison::Eq,
+            "!=" => Comparison::Ne,
+            "<" => Comparison::Lt,
+            "<=" => Comparison::Le,
+            ">" => Comparison::Gt,
+            ">=" => Comparison::Ge,
+            other => return Err(format!("Unknown comparison {}", other)),
+        };
+        Ok(Self {
+            register,
+            comparison,
+            value: *address(words[2])?,
+        })
+    }
+}
+
+/// Stops before running the instruction at `addr`, if the condition holds
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub addr: Address,
+    pub condition: Option<Condition>,
+}
+impl FromStr for Breakpoint {
+    type Err = String;
+    /// `$0600`, or `$0600 if X >= 3`
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        let (addr, condition) = match text.find(" if ") {
+            Some(i) => (&text[..i], Some(text[i + 4..].parse()?)),
+            None => (text, None),
+        };
+        Ok(Self {
+            addr: address(addr.trim())?,
+            condition,
+        })
+    }
+}
+/* #endregion */
+
+fn address(text: &str) -> Result<Address, String> {
+    text.parse()
+        .map_err(|e| format!("Invalid address {}: {}", text, e))
+}
+
+/// Why a run stopped
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StopReason {
+    /// Stopped by a command
+    Requested,
+    Breakpoint(Address),
+    Watchpoint(WatchHit),
+    /// Got to the address it was running to
+    Reached(Address),
+    /// Ran for the number of cycles it was asked to
+    CyclesElapsed(usize),
+    /// Stepped out of a subroutine, back to this address
+    Returned(Address),
+    /// The CPU couldn't run the instruction at this address
+    Fault(Address),
+}
+impl Display for StopReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        match self {
+            StopReason::Requested => write!(f, "Stopped"),
+            StopReason::Breakpoint(addr) => write!(f, "Breakpoint at ${:04X}", **addr),
+            StopReason::Watchpoint(hit) => write!(
+                f,
+                "Watchpoint: {} ${:04X} (${:02X})",
+                match hit.access {
+                    Access::Write => "write to",
+                    _ => "read from",
+                },
+                *hit.addr,
+                hit.value
+            ),
+            StopReason::Reached(addr) => write!(f, "Reached ${:04X}", **addr),
+            StopReason::CyclesElapsed(cycles) => write!(f, "Ran for {} cycles", cycles),
+            StopReason::Returned(addr) => write!(f, "Returned to ${:04X}", **addr),
+            StopReason::Fault(addr) => write!(f, "Faulted at ${:04X}", **addr),
+        }
+    }
+}
+
+/// The breakpoints of a session. Watchpoints live in `System`, which sees the accesses
+#[derive(Clone, Debug, Default)]
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+}
+impl Debugger {
+    /// Replaces any other breakpoint at the same address
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.remove_breakpoint(breakpoint.addr);
+        self.breakpoints.push(breakpoint);
+    }
+    pub fn remove_breakpoint(&mut self, addr: Address) {
+        self.breakpoints.retain(|b| b.addr != addr);
+    }
+    /// Whether the last step should stop a run, and why
+    pub fn check(&self, system: &mut System) -> Option<StopReason> {
+        if let Some(hit) = system.take_watch_hit() {
+            return Some(StopReason::Watchpoint(hit));
+        }
+        let pc = system.registers.PC;
+        let hit = self
+            .breakpoints
+            .iter()
+            .any(|b| b.addr == pc && b.condition.is_none_or(|c| c.holds(&system.registers)));
+        if hit {
+            Some(StopReason::Breakpoint(pc))
+        } else {
+            None
+        }
+    }
+}
+
+/// Where the JSR at PC returns to, which is where Step Over stops.
+/// `None` when the next instruction isn't a JSR, so stepping over it is a plain step
+pub fn return_address(system: &System) -> Option<Address> {
+    let pc = system.registers.PC;
+    match from_code(system.bus.peek(pc)) {
+        Some(code) if code.name == OpcodeType::JSR => Some(pc.wrapping_add(3usize)),
+        _ => None,
+    }
+}
+/// Whether `code` is RTS or RTI, the instructions Step Out waits for
+pub fn is_return(code: u8) -> bool {
+    match from_code(code) {
+        Some(code) => code.name == OpcodeType::RTS || code.name == OpcodeType::RTI,
+        None => false,
+    }
+}
+
+mod test {
+    #[test]
+    fn test_debugger() {
+        use super::{Access, Breakpoint, Debugger, StopReason, WatchHit, Watchpoint};
+        use crate::emulator::addressing_modes::Address;
+        use crate::emulator::system::System;
+        let mut system = System::new();
+        // LDX #0; loop: INX; STX $0200; JMP loop
+        let program = [0xA2, 0x00, 0xE8, 0x8E, 0x00, 0x02, 0x4C, 0x02, 0x06];
+        system.bus.load(Address(0x0600), &program);
+        system.bus.load(Address(0xFFFC), &[0x00, 0x06]);
+        let mut debugger = Debugger::default();
+        debugger.add_breakpoint("$0603 if X == 3".parse().unwrap());
+        system
+            .watchpoints
+            .push("write $0200-$02FF".parse().unwrap());
+        let mut stops = vec![];
+        while stops.len() < 4 {
+            system.step().unwrap();
+            if let Some(reason) = debugger.check(&mut system) {
+                stops.push(reason);
+            }
+        }
+        let write = |value| {
+            StopReason::Watchpoint(WatchHit {
+                addr: Address(0x0200),
+                access: Access::Write,
+                value,
+            })
+        };
+        assert_eq!(
+            stops,
+            vec![
+                write(1),
+                write(2),
+                StopReason::Breakpoint(Address(0x0603)),
+                write(3)
+            ]
+        );
+        assert_eq!(super::return_address(&system), None);
+        assert!(super::is_return(0x60));
+        assert!("read $10-$0F".parse::<Watchpoint>().is_err());
+        assert!("$0600 if Q == 1".parse::<Breakpoint>().is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use super::addressing_modes::{get_size, Address, AddressingMode};
+use super::bus::Bus;
+use super::opcodes::from_code;
+use std::fmt::{Display, Error as FmtError, Formatter};
+
+/// One decoded instruction
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    pub address: Address,
+    pub bytes: Vec<u8>,
+    /// `LDA #$10`, or `.byte $02` when the opcode is undefined
+    pub text: String,
+}
+impl Display for Line {
+    /// `0600  A9 10     LDA #$10`
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(
+            f,
+            "{:04X}  {:<8}  {}",
+            *self.address,
+            bytes.join(" "),
+            self.text
+        )
+    }
+}
+
+/// Decodes the instruction at `addr`. Memory is peeked, so devices don't notice
+pub fn decode(bus: &dyn Bus, addr: Address) -> Line {
+    let code = bus.peek(addr);
+    let opcode = match from_code(code) {
+        Some(opcode) => opcode,
+        None => {
+            return Line {
+                address: addr,
+                bytes: vec![code],
+                text: format!(".byte ${:02X}", code),
+            }
+        }
+    };
+    let bytes: Vec<u8> = (0..get_size(opcode.addr_mode))
+        .map(|i| bus.peek(addr.wrapping_add(i)))
+        .collect();
+    let byte = || bytes[1];
+    let word = || (bytes[2] as u16) << 8 | bytes[1] as u16;
+    let operand = match opcode.addr_mode {
+        AddressingMode::IMPL => String::new(),
+        AddressingMode::A => "A".to_string(),
+        AddressingMode::IMM => format!("#${:02X}", byte()),
+        AddressingMode::ZPG => format!("${:02X}", byte()),
+        AddressingMode::ZPGX => format!("${:02X},X", byte()),
+        AddressingMode::ZPGY => format!("${:02X},Y", byte()),
+        AddressingMode::ABS => format!("${:04X}", word()),
+        AddressingMode::ABSX => format!("${:04X},X", word()),
+        AddressingMode::ABSY => format!("${:04X},Y", word()),
+        AddressingMode::IND => format!("(${:04X})", word()),
+        AddressingMode::INDX => format!("(${:02X},X)", byte()),
+        AddressingMode::INDY => format!("(${:02X}),Y", byte()),
+        AddressingMode::REL => {
+            // Relative to the next instruction
+            let target = (*addr as isize + 2 + byte() as i8 as isize) & 0xFFFF;
+            format!("${:04X}", target)
+        }
+    };
+    let mnemonic: String = opcode.name.into();
+    Line {
+        address: addr,
+        text: if operand.is_empty() {
+            mnemonic
+        } else {
+            format!("{} {}", mnemonic, operand)
+        },
+        bytes,
+    }
+}
+
+/// Decodes every instruction that starts in `start..=end`
+pub fn disassemble(bus: &dyn Bus, start: Address, end: Address) -> Vec<Line> {
+    let mut lines = vec![];
+    let mut addr = *start;
+    while addr <= *end && addr <= 0xFFFF {
+        let line = decode(bus, Address(addr));
+        addr += line.bytes.len();
+        lines.push(line);
+    }
+    lines
+}
+
+/// Up to `before` instructions leading to `pc`, the one at `pc`, and `after` more.
+/// Code can't be decoded backwards reliably, so the lines before `pc` come from the
+/// furthest start that lands on `pc` going through the fewest undefined opcodes
+pub fn around(bus: &dyn Bus, pc: Address, before: usize, after: usize) -> Vec<Line> {
+    let mut lines = vec![];
+    // Undefined opcodes in `lines`
+    let mut best = usize::MAX;
+    // Instructions are at most 3 bytes long
+    for back in (1..=before * 3).rev().filter(|back| *back <= *pc) {
+        let mut candidate = vec![];
+        let mut addr = *pc - back;
+        while addr < *pc {
+            let line = decode(bus, Address(addr));
+            addr += line.bytes.len();
+            candidate.push(line);
+        }
+        let undefined = candidate
+            .iter()
+            .filter(|line| from_code(line.bytes[0]).is_none())
+            .count();
+        if addr == *pc && undefined < best {
+            lines = candidate;
+            best = undefined;
+            if best == 0 {
+                break;
+            }
+        }
+    }
+    let skip = lines.len().saturating_sub(before);
+    lines.drain(..skip);
+    let mut addr = pc;
+    for _ in 0..=after {
+        let line = decode(bus, addr);
+        addr = addr.wrapping_add(line.bytes.len());
+        lines.push(line);
+    }
+    lines
+}
+
+mod test {
+    #[test]
+    fn test_disassemble() {
+        use super::{around, disassemble};
+        use crate::emulator::addressing_modes::Address;
+        use crate::emulator::bus::Bus;
+        use crate::emulator::components::Ram;
+        let mut ram = Ram::new();
+        let program = [
+            0xA9, 0x10, // LDA #$10
+            0x9D, 0x00, 0x02, // STA $0200,X
+            0xB1, 0x40, // LDA ($40),Y
+            0x6C, 0xFC, 0xFF, // JMP ($FFFC)
+            0xD0, 0xF4, // BNE $0600
+            0x02, // Undefined
+            0x0A, // ASL A
+        ];
+        ram.load(Address(0x0600), &program);
+        let lines: Vec<String> = disassemble(&ram, Address(0x0600), Address(0x060D))
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "0600  A9 10     LDA #$10",
+                "0602  9D 00 02  STA $0200,X",
+                "0605  B1 40     LDA ($40),Y",
+                "0607  6C FC FF  JMP ($FFFC)",
+                "060A  D0 F4     BNE $0600",
+                "060C  02        .byte $02",
+                "060D  0A        ASL A",
+            ]
+        );
+        let lines = around(&ram, Address(0x0607), 2, 1);
+        let addresses: Vec<usize> = lines.iter().map(|line| *line.address).collect();
+        assert_eq!(addresses, vec![0x0602, 0x0605, 0x0607, 0x060A]);
+    }
+}
@@ -1,27 +1,229 @@
+use super::addressing_modes::{Address, AddressingMode};
 use std::boxed::Box;
 use std::error::Error;
 use std::fmt::{Display, Error as FmtError, Formatter};
+use std::path::PathBuf;
 
+/// Why the CPU couldn't run an instruction, and which one it was
 #[derive(Debug)]
-pub enum CpuError {
-    UnknownOp(u8),
-    Break,
-    Suberror(Box<dyn Error>),
+pub struct CpuError {
+    pub pc: Address,
+    pub opcode: u8,
+    pub kind: CpuErrorKind,
+}
+#[derive(Debug)]
+pub enum CpuErrorKind {
+    UnknownOp,
+    /// An undocumented opcode whose result depends on the chip
+    UnstableOp,
+    /// The instruction needs an operand its addressing mode doesn't give
+    InvalidMode(AddressingMode),
+    /// An address computation went past $FFFF, or below $0000
+    AddressOverflow(i64),
+    Suberror(Box<dyn Error + Send + Sync>),
 }
 
 impl Display for CpuError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        let message = match &self.kind {
+            CpuErrorKind::Suberror(e) => e.to_string(),
+            CpuErrorKind::UnknownOp => "Unknown OP".to_string(),
+            CpuErrorKind::UnstableOp => "Unstable undocumented OP".to_string(),
+            CpuErrorKind::InvalidMode(mode) => {
+                format!("The instruction can't use {:?} addressing", mode)
+            }
+            CpuErrorKind::AddressOverflow(value) => {
+                format!("Address {} is outside $0000-$FFFF", value)
+            }
+        };
+        write!(
+            f,
+            "Emulator Error: {} (opcode ${:02X} at ${:04X})",
+            message, self.opcode, *self.pc
+        )
+    }
+}
+impl Error for CpuError {}
+
+#[derive(Debug)]
+pub enum MachineError {
+    Io(PathBuf, std::io::Error),
+    Parse(toml::de::Error),
+    InvalidRange {
+        start: usize,
+        end: usize,
+    },
+    RomTooBig {
+        file: PathBuf,
+        size: usize,
+        window: usize,
+    },
+    UnknownDevice(String),
+}
+
+impl Display for MachineError {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
         write!(
             f,
-            "Emulator Error: {}",
+            "Machine Error: {}",
             match self {
-                CpuError::Suberror(e) => e.description().to_string(),
-                CpuError::Break => "Emulator Terminated".to_string(),
-                CpuError::UnknownOp(code) => format!("Unknown OP with code {:02X}", code),
+                MachineError::Io(path, e) => format!("Couldn't read {}: {}", path.display(), e),
+                MachineError::Parse(e) => format!("Invalid machine file: {}", e),
+                MachineError::InvalidRange { start, end } => {
+                    format!("Invalid range ${:04X}-${:04X}", start, end)
+                }
+                MachineError::RomTooBig { file, size, window } => format!(
+                    "{} is {} bytes long, but its window only has {}",
+                    file.display(),
+                    size,
+                    window
+                ),
+                MachineError::UnknownDevice(name) => format!("Unknown device \"{}\"", name),
             }
         )
     }
 }
-impl Error for CpuError {}
-unsafe impl std::marker::Send for CpuError {}
-unsafe impl std::marker::Sync for CpuError {}
+impl Error for MachineError {}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    TooShort,
+    BadRecord(usize),
+    Checksum {
+        line: usize,
+        expected: u8,
+        found: u8,
+    },
+    OutOfRange(usize),
+    NoEntryPoint,
+    NotText,
+    Assembly(AsmError),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        write!(
+            f,
+            "Load Error: {}",
+            match self {
+                LoadError::Io(e) => e.to_string(),
+                LoadError::TooShort => "File too short for its format".to_string(),
+                LoadError::BadRecord(line) => format!("Malformed record on line {}", line),
+                LoadError::Checksum {
+                    line,
+                    expected,
+                    found,
+                } => format!(
+                    "Bad checksum on line {}: expected {:02X}, found {:02X}",
+                    line, expected, found
+                ),
+                LoadError::OutOfRange(line) => {
+                    format!("Line {} goes past the 64 KiB address space", line)
+                }
+                LoadError::NoEntryPoint => "The image has no entry point".to_string(),
+                LoadError::NotText => "The file isn't valid UTF-8 text".to_string(),
+                LoadError::Assembly(e) => e.to_string(),
+            }
+        )
+    }
+}
+impl Error for LoadError {}
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(std::io::Error),
+    /// The file isn't a save state
+    NotAState,
+    /// Saved by a newer version of the format
+    Version(u16),
+    Truncated,
+    /// Saved on a machine with different devices
+    Mismatch,
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        write!(
+            f,
+            "State Error: {}",
+            match self {
+                StateError::Io(e) => e.to_string(),
+                StateError::NotAState => "The file isn't a save state".to_string(),
+                StateError::Version(version) => {
+                    format!("Version {} save states aren't supported", version)
+                }
+                StateError::Truncated => "The save state is cut short".to_string(),
+                StateError::Mismatch => {
+                    "The save state is from a machine with other devices".to_string()
+                }
+            }
+        )
+    }
+}
+impl Error for StateError {}
+
+/// An assembler error, and where in the source it happened
+#[derive(Debug)]
+pub struct AsmError {
+    pub file: PathBuf,
+    /// Starts at 1. 0 when the error isn't about a specific line
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+#[derive(Debug)]
+pub enum AsmErrorKind {
+    Io(std::io::Error),
+    Syntax(String),
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    UnknownSymbol(String),
+    Redefined(String),
+    InvalidMode {
+        mnemonic: String,
+        mode: &'static str,
+    },
+    OutOfRange(i64),
+    BranchTooFar(i64),
+    DivisionByZero,
+    AddressOverflow,
+    IncludeDepth,
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        let message = match &self.kind {
+            AsmErrorKind::Io(e) => e.to_string(),
+            AsmErrorKind::Syntax(message) => message.clone(),
+            AsmErrorKind::UnknownMnemonic(name) => format!("Unknown instruction \"{}\"", name),
+            AsmErrorKind::UnknownDirective(name) => format!("Unknown directive \"{}\"", name),
+            AsmErrorKind::UnknownSymbol(name) => format!("Unknown symbol \"{}\"", name),
+            AsmErrorKind::Redefined(name) => format!("\"{}\" is already defined", name),
+            AsmErrorKind::InvalidMode { mnemonic, mode } => {
+                format!("{} doesn't support {} addressing", mnemonic, mode)
+            }
+            AsmErrorKind::OutOfRange(value) => format!("Value {} is out of range", value),
+            AsmErrorKind::BranchTooFar(offset) => {
+                format!(
+                    "Branch target is {} bytes away, the limit is -128..127",
+                    offset
+                )
+            }
+            AsmErrorKind::DivisionByZero => "Division by zero".to_string(),
+            AsmErrorKind::AddressOverflow => "Code goes past $FFFF".to_string(),
+            AsmErrorKind::IncludeDepth => "Includes are nested too deep".to_string(),
+        };
+        if self.line == 0 {
+            write!(f, "Assembler Error: {}: {}", self.file.display(), message)
+        } else {
+            write!(
+                f,
+                "Assembler Error: {}:{}: {}",
+                self.file.display(),
+                self.line,
+                message
+            )
+        }
+    }
+}
+impl Error for AsmError {}
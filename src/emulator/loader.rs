@@ -0,0 +1,331 @@
+use super::addressing_modes::Address;
+use super::assembler;
+use super::bus::Bus;
+use super::error::LoadError;
+use std::path::Path;
+
+static RESET_VEC_ADDR: Address = Address(0xFFFC);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    /// Plain bytes, placed at the given address
+    Raw(Address),
+    IntelHex,
+    /// Motorola S-record (S19, S28 and S37)
+    SRecord,
+    /// Commodore program: a little endian load address followed by the bytes
+    Prg,
+    /// Source code, put through the assembler
+    Assembly,
+}
+impl Format {
+    /// Guesses the format from the file extension. Anything unknown is raw data at $0000
+    pub fn guess<P: AsRef<Path>>(path: P) -> Self {
+        let ext = path
+            .as_ref()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("hex") | Some("ihex") | Some("ihx") => Format::IntelHex,
+            Some("s19") | Some("s28") | Some("s37") | Some("srec") | Some("mot") => Format::SRecord,
+            Some("prg") => Format::Prg,
+            Some("s") | Some("asm") | Some("a65") => Format::Assembly,
+            _ => Format::Raw(Address(0x0000)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub struct LoadOptions {
+    /// Guessed from the file extension when missing
+    pub format: Option<Format>,
+    /// Points the reset vector to the entry point of the image
+    pub set_reset_vector: bool,
+}
+
+/// A parsed program: the bytes to write, and where execution should start
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Image {
+    pub chunks: Vec<(Address, Vec<u8>)>,
+    pub entry: Option<Address>,
+}
+impl Image {
+    pub fn parse(format: Format, data: &[u8]) -> Result<Self, LoadError> {
+        match format {
+            Format::Raw(start) => Ok(Image {
+                chunks: vec![(start, data.to_vec())],
+                entry: Some(start),
+            }),
+            Format::Prg => {
+                if data.len() < 2 {
+                    return Err(LoadError::TooShort);
+                }
+                let start = Address((data[1] as usize) << 8 | data[0] as usize);
+                Ok(Image {
+                    chunks: vec![(start, data[2..].to_vec())],
+                    entry: Some(start),
+                })
+            }
+            Format::IntelHex => parse_intel_hex(data),
+            Format::SRecord => parse_srecord(data),
+            Format::Assembly => {
+                let source = std::str::from_utf8(data).map_err(|_| LoadError::NotText)?;
+                Ok(assembler::assemble(source)
+                    .map_err(LoadError::Assembly)?
+                    .image)
+            }
+        }
+    }
+    /// Writes the image into `bus`, returning the ranges written to.
+    /// Ranges are inclusive, sorted, and merged when they touch
+    pub fn load_into(&self, bus: &mut dyn Bus) -> Vec<(Address, Address)> {
+        let mut ranges: Vec<(usize, usize)> = vec![];
+        for (start, data) in self.chunks.iter().filter(|(_, data)| !data.is_empty()) {
+            bus.load(*start, data);
+            let end = **start + data.len() - 1;
+            if end > 0xFFFF {
+                // The bus wraps around to $0000
+                ranges.push((**start, 0xFFFF));
+                ranges.push((0x0000, end & 0xFFFF));
+            } else {
+                ranges.push((**start, end));
+            }
+        }
+        ranges.sort();
+        let mut merged: Vec<(usize, usize)> = vec![];
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        merged
+            .into_iter()
+            .map(|(start, end)| (Address(start), Address(end)))
+            .collect()
+    }
+}
+
+/// Parses and loads `data` into `bus`, returning the ranges written to
+pub fn load(
+    bus: &mut dyn Bus,
+    format: Format,
+    data: &[u8],
+    set_reset_vector: bool,
+) -> Result<Vec<(Address, Address)>, LoadError> {
+    load_image(bus, &Image::parse(format, data)?, set_reset_vector)
+}
+fn load_image(
+    bus: &mut dyn Bus,
+    image: &Image,
+    set_reset_vector: bool,
+) -> Result<Vec<(Address, Address)>, LoadError> {
+    let ranges = image.load_into(bus);
+    if set_reset_vector {
+        let entry = image.entry.ok_or(LoadError::NoEntryPoint)?;
+        bus.load(RESET_VEC_ADDR, &[*entry as u8, (*entry >> 8) as u8]);
+    }
+    Ok(ranges)
+}
+
+/// Reads the file at `path` and loads it into `bus`
+pub fn load_file<P: AsRef<Path>>(
+    bus: &mut dyn Bus,
+    path: P,
+    options: LoadOptions,
+) -> Result<Vec<(Address, Address)>, LoadError> {
+    let path = path.as_ref();
+    let format = options.format.unwrap_or_else(|| Format::guess(path));
+    if format == Format::Assembly {
+        // Includes are relative to the file
+        let image = assembler::assemble_file(path)
+            .map_err(LoadError::Assembly)?
+            .image;
+        return load_image(bus, &image, options.set_reset_vector);
+    }
+    let data = std::fs::read(path).map_err(LoadError::Io)?;
+    load(bus, format, &data, options.set_reset_vector)
+}
+
+/* #region Text formats */
+/// Decodes the hex digits of a record, after its start code
+fn record_bytes(line: usize, digits: &str) -> Result<Vec<u8>, LoadError> {
+    if !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+        return Err(LoadError::BadRecord(line));
+    }
+    let byte = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16);
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| byte(i).map_err(|_| LoadError::BadRecord(line)))
+        .collect()
+}
+fn lines(data: &[u8]) -> Result<impl Iterator<Item = (usize, &str)>, LoadError> {
+    let text = std::str::from_utf8(data).map_err(|_| LoadError::BadRecord(1))?;
+    Ok(text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty()))
+}
+fn address(line: usize, addr: usize) -> Result<Address, LoadError> {
+    if addr > 0xFFFF {
+        return Err(LoadError::OutOfRange(line));
+    }
+    Ok(Address(addr))
+}
+
+fn parse_intel_hex(data: &[u8]) -> Result<Image, LoadError> {
+    let mut image = Image::default();
+    let mut base = 0usize; // Set by extended address records
+    for (line, text) in lines(data)? {
+        if !text.starts_with(':') {
+            return Err(LoadError::BadRecord(line));
+        }
+        let bytes = record_bytes(line, &text[1..])?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(LoadError::BadRecord(line));
+        }
+        // Every byte, checksum included, adds up to 0
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if sum != 0 {
+            let found = bytes[bytes.len() - 1];
+            return Err(LoadError::Checksum {
+                line,
+                expected: found.wrapping_sub(sum),
+                found,
+            });
+        }
+        let offset = (bytes[1] as usize) << 8 | bytes[2] as usize;
+        let payload = &bytes[4..bytes.len() - 1];
+        let word = |payload: &[u8]| payload.iter().fold(0usize, |n, b| n << 8 | *b as usize);
+        // Address records always carry a 2 or 4 byte value
+        let size = match bytes[3] {
+            0x02 | 0x04 => 2,
+            0x03 | 0x05 => 4,
+            _ => payload.len(),
+        };
+        if payload.len() != size {
+            return Err(LoadError::BadRecord(line));
+        }
+        match bytes[3] {
+            0x00 => {
+                let start = base
+                    .checked_add(offset)
+                    .filter(|start| *start <= 0x10000 - payload.len())
+                    .ok_or(LoadError::OutOfRange(line))?;
+                image.chunks.push((Address(start), payload.to_vec()));
+            }
+            0x01 => break,
+            0x02 => base = word(payload) << 4,
+            0x04 => base = word(payload) << 16,
+            0x03 => {
+                // CS:IP
+                let (cs, ip) = (word(&payload[..2]), word(&payload[2..]));
+                image.entry = Some(address(line, (cs << 4) + ip)?);
+            }
+            0x05 => image.entry = Some(address(line, word(payload))?),
+            _ => return Err(LoadError::BadRecord(line)),
+        }
+    }
+    Ok(image)
+}
+
+fn parse_srecord(data: &[u8]) -> Result<Image, LoadError> {
+    let mut image = Image::default();
+    for (line, text) in lines(data)? {
+        if text.len() < 2 || !text.starts_with('S') {
+            return Err(LoadError::BadRecord(line));
+        }
+        let kind = text.as_bytes()[1];
+        let bytes = record_bytes(line, &text[2..])?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(LoadError::BadRecord(line));
+        }
+        // The checksum is the ones' complement of the sum of the other bytes
+        let (body, found) = bytes.split_at(bytes.len() - 1);
+        let expected = !body.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if expected != found[0] {
+            return Err(LoadError::Checksum {
+                line,
+                expected,
+                found: found[0],
+            });
+        }
+        let addr_len = match kind {
+            b'0' | b'1' | b'5' | b'9' => 2,
+            b'2' | b'6' | b'8' => 3,
+            b'3' | b'7' => 4,
+            _ => return Err(LoadError::BadRecord(line)),
+        };
+        if body.len() < 1 + addr_len {
+            return Err(LoadError::BadRecord(line));
+        }
+        let addr = body[1..=addr_len]
+            .iter()
+            .fold(0usize, |n, b| n << 8 | *b as usize);
+        let payload = &body[1 + addr_len..];
+        match kind {
+            b'1' | b'2' | b'3' => {
+                if addr + payload.len() > 0x10000 {
+                    return Err(LoadError::OutOfRange(line));
+                }
+                image.chunks.push((Address(addr), payload.to_vec()));
+            }
+            b'7' | b'8' | b'9' => image.entry = Some(address(line, addr)?),
+            _ => {} // Header and record counts
+        }
+    }
+    Ok(image)
+}
+/* #endregion */
+
+mod test {
+    #[test]
+    fn test_formats() {
+        use super::{load, Format};
+        use crate::emulator::addressing_modes::Address;
+        use crate::emulator::bus::Bus;
+        use crate::emulator::components::Ram;
+        use crate::emulator::error::LoadError;
+        let mut ram = Ram::new();
+        let hex = b":030600000A0B0CD6\n:04FFFC0000060000FB\n:00000001FF\n";
+        let ranges = load(&mut ram, Format::IntelHex, hex, false).unwrap();
+        assert_eq!(
+            ranges,
+            vec![
+                (Address(0x0600), Address(0x0602)),
+                (Address(0xFFFC), Address(0xFFFF))
+            ]
+        );
+        assert_eq!(ram.peek(Address(0x0602)), 0x0C);
+        let srec = b"S1060700010203EC\nS9030700F5\n";
+        load(&mut ram, Format::SRecord, srec, true).unwrap();
+        assert_eq!(ram.peek(Address(0x0702)), 0x03);
+        assert_eq!(ram.peek(Address(0xFFFD)), 0x07);
+        let prg = [0x01, 0x08, 0xEA, 0xEA];
+        let ranges = load(&mut ram, Format::Prg, &prg, false).unwrap();
+        assert_eq!(ranges, vec![(Address(0x0801), Address(0x0802))]);
+        let bad = b":030600000A0B0CD7\n";
+        match load(&mut ram, Format::IntelHex, bad, false) {
+            Err(LoadError::Checksum { line: 1, .. }) => {}
+            other => panic!("Expected a checksum error, got {:?}", other),
+        }
+        // Address records that are too short or point past 64 KiB
+        let short = b":00000003FD\n";
+        assert!(matches!(
+            load(&mut ram, Format::IntelHex, short, false),
+            Err(LoadError::BadRecord(1))
+        ));
+        let short = b":00000002FE\n";
+        assert!(matches!(
+            load(&mut ram, Format::IntelHex, short, false),
+            Err(LoadError::BadRecord(1))
+        ));
+        let far = b":0200000400FFFB\n:0100000000FF\n";
+        assert!(matches!(
+            load(&mut ram, Format::IntelHex, far, false),
+            Err(LoadError::OutOfRange(2))
+        ));
+    }
+}
@@ -0,0 +1,235 @@
+use super::addressing_modes::Address;
+use super::bus::{Bus, Memory, MemoryMap, OpenBus, Random};
+use super::error::MachineError;
+use super::system::{IllegalOpcodes, System};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Layout of a machine, usually read from a TOML file:
+/// ```toml
+/// illegal_opcodes = "emulate" # halt (the default), nop or emulate
+///
+/// [[ram]]
+/// start = 0x0000
+/// end = 0x07FF
+///
+/// [[mirror]] # $0800-$1FFF repeats the first 2 KiB
+/// start = 0x0800
+/// end = 0x1FFF
+/// target = 0x0000
+/// size = 0x0800
+///
+/// [[rom]] # Paths are relative to the machine file
+/// start = 0xC000
+/// file = "monitor.bin"
+///
+/// [[protect]]
+/// start = 0x0000
+/// end = 0x00FF
+///
+/// [[device]] # One of the built-in devices, see `builtin_device`
+/// name = "random"
+/// start = 0x00FE
+/// end = 0x00FE
+/// ```
+/// Anything left unmapped reads as $00 and ignores writes
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct MachineConfig {
+    #[serde(default)]
+    pub ram: Vec<Region>,
+    #[serde(default)]
+    pub rom: Vec<RomRegion>,
+    #[serde(default)]
+    pub protect: Vec<Region>,
+    #[serde(default)]
+    pub mirror: Vec<MirrorRegion>,
+    #[serde(default)]
+    pub device: Vec<DeviceWindow>,
+    #[serde(default)]
+    pub illegal_opcodes: IllegalOpcodes,
+    /// Where relative ROM paths start from
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+}
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RomRegion {
+    pub start: usize,
+    /// Defaults to the last byte of the file
+    pub end: Option<usize>,
+    pub file: PathBuf,
+}
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorRegion {
+    pub start: usize,
+    pub end: usize,
+    pub target: usize,
+    pub size: usize,
+}
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceWindow {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+fn range(start: usize, end: usize) -> Result<(Address, Address), MachineError> {
+    if start > end || end > 0xFFFF {
+        return Err(MachineError::InvalidRange { start, end });
+    }
+    Ok((Address(start), Address(end)))
+}
+
+impl MachineConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, MachineError> {
+        let path = path.as_ref();
+        let text =
+            std::fs::read_to_string(path).map_err(|e| MachineError::Io(path.to_path_buf(), e))?;
+        let mut config: Self = text.parse()?;
+        config.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(config)
+    }
+    /// Builds the system with the devices from `builtin_device`
+    pub fn build(&self) -> Result<System, MachineError> {
+        self.build_with(builtin_device)
+    }
+    /// Builds the system, creating devices by name with `make_device`.
+    /// Regions are mapped in file order: RAM, ROM and then devices,
+    /// so a device window can be placed on top of RAM
+    pub fn build_with<F>(&self, make_device: F) -> Result<System, MachineError>
+    where
+        F: Fn(&str) -> Option<Box<dyn Bus>>,
+    {
+        let mut map = MemoryMap::new(Box::new(OpenBus));
+        for region in self.ram.iter() {
+            let (start, end) = range(region.start, region.end)?;
+            map.map(start, end, Box::new(Memory::ram(*end - *start + 1)));
+        }
+        for region in self.rom.iter() {
+            let file = self.base_dir.join(&region.file);
+            let data = std::fs::read(&file).map_err(|e| MachineError::Io(file.clone(), e))?;
+            let end = region
+                .end
+                .unwrap_or_else(|| region.start + data.len().max(1) - 1);
+            let (start, end) = range(region.start, end)?;
+            let window = *end - *start + 1;
+            if data.len() > window {
+                return Err(MachineError::RomTooBig {
+                    file,
+                    size: data.len(),
+                    window,
+                });
+            }
+            let mut rom = Memory::rom(vec![0x00; window]);
+            rom.load(Address(0), &data);
+            map.map(start, end, Box::new(rom));
+        }
+        for device in self.device.iter() {
+            let (start, end) = range(device.start, device.end)?;
+            let bus = make_device(&device.name)
+                .ok_or_else(|| MachineError::UnknownDevice(device.name.clone()))?;
+            map.map(start, end, bus);
+        }
+        for region in self.protect.iter() {
+            let (start, end) = range(region.start, region.end)?;
+            map.protect(start, end);
+        }
+        for mirror in self.mirror.iter() {
+            let (start, end) = range(mirror.start, mirror.end)?;
+            let (target, _) = range(mirror.target, mirror.target)?;
+            map.mirror(start, end, target, mirror.size);
+        }
+        let mut system = System::with_bus(Box::new(map));
+        system.illegal_opcodes = self.illegal_opcodes;
+        Ok(system)
+    }
+}
+impl FromStr for MachineConfig {
+    type Err = MachineError;
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        toml::from_str(text).map_err(MachineError::Parse)
+    }
+}
+
+/// The devices any machine file can use:
+/// - `random`: reads give a new random byte every time
+pub fn builtin_device(name: &str) -> Option<Box<dyn Bus>> {
+    match name {
+        "random" => Some(Box::new(Random::new())),
+        _ => None,
+    }
+}
+
+mod test {
+    #[test]
+    fn test_machine_config() {
+        use super::MachineConfig;
+        use crate::emulator::addressing_modes::Address;
+        let config: MachineConfig = "
+            illegal_opcodes = \"nop\"
+
+            [[ram]]
+            start = 0x0000
+            end = 0x07FF
+
+            [[mirror]]
+            start = 0x0800
+            end = 0x1FFF
+            target = 0x0000
+            size = 0x0800
+
+            [[protect]]
+            start = 0x0100
+            end = 0x01FF
+            "
+        .parse()
+        .unwrap();
+        let mut system = config.build().unwrap();
+        assert_eq!(system.illegal_opcodes, super::IllegalOpcodes::Nop);
+        system.bus.write(Address(0x1805), 0x42);
+        assert_eq!(system.bus.peek(Address(0x0005)), 0x42);
+        system.bus.write(Address(0x0100), 0x42);
+        assert_eq!(system.bus.peek(Address(0x0100)), 0x00);
+        assert_eq!(system.bus.peek(Address(0x8000)), 0x00);
+        assert!("[[ram]]\nstart = 0x10\nend = 0x01"
+            .parse::<MachineConfig>()
+            .unwrap()
+            .build()
+            .is_err());
+
+        // Devices go on top of RAM
+        let config: MachineConfig = "
+            [[ram]]
+            start = 0x0000
+            end = 0xFFFF
+
+            [[device]]
+            name = \"random\"
+            start = 0x00FE
+            end = 0x00FE
+            "
+        .parse()
+        .unwrap();
+        let mut system = config.build().unwrap();
+        let first = system.bus.read(Address(0x00FE));
+        assert_ne!(system.bus.read(Address(0x00FE)), first);
+        system.bus.write(Address(0x00FD), 0x42);
+        assert_eq!(system.bus.peek(Address(0x00FD)), 0x42);
+        let unknown = config.build_with(|_| None);
+        assert!(matches!(
+            unknown,
+            Err(super::MachineError::UnknownDevice(_))
+        ));
+    }
+}
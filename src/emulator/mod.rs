@@ -1,10 +1,29 @@
+//! The 6502 and everything around it: `System` runs the CPU on a `Bus`, which can be a
+//! flat `Ram` or a `MemoryMap` of devices built from a `MachineConfig`.
+//! `loader` and `assembler` put programs in memory, `disassembler`, `debugger` and `trace`
+//! look at them while they run
 mod error;
-pub use error::CpuError;
+pub use error::{
+    AsmError, AsmErrorKind, CpuError, CpuErrorKind, LoadError, MachineError, StateError,
+};
 
 mod addressing_modes;
-use addressing_modes::AddressingMode;
+pub use addressing_modes::{Address, AddressingMode};
+mod bus;
+pub use bus::{Bus, Memory, MemoryMap, OpenBus, Random};
 mod opcodes;
 use opcodes::OpcodeType;
+pub use opcodes::{is_documented, is_stable};
 mod components;
+pub use components::{Flags, Ram, Registers};
 mod system;
-pub use system::System;
+pub use system::{IllegalOpcodes, System};
+mod machine;
+pub use machine::{builtin_device, MachineConfig};
+pub mod assembler;
+pub mod debugger;
+pub mod disassembler;
+pub mod loader;
+pub mod rewind;
+pub mod snapshot;
+pub mod trace;
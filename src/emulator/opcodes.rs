@@ -711,19 +711,36 @@ pub const OPCODES: [Option<OpcodeData>; 0x100] = [
     }),
     None,
 ];
-/* pub fn get_code(name: OpcodeType, addr_mode: AddressingMode) -> Result<u8, Error> {
-    for (i, opcode) in OPCODES.iter().enumerate() {
-        match opcode {
-            None => continue,
-            Some(ref opcode) => {
-                if opcode.name == name && opcode.addr_mode == addr_mode {
-                    return Ok((i & 0xFF) as u8);
-                }
-            }
-        }
-    }
-    Err(Error::UnkownOpcode { name: name.into() })
-} */
+/// Base cycle count of every opcode, without page crossing or branch penalties
+//   0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
+pub static OP_CYCLES: [u8; 0x100] = [
+    7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6, // 0
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 1
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6, // 2
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 3
+    6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6, // 4
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 5
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6, // 6
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 7
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // 8
+    2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5, // 9
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // A
+    2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4, // B
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // C
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // D
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // E
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // F
+];
+/// Finds the opcode for an instruction, if it has that addressing mode
+pub fn get_code(name: OpcodeType, addr_mode: AddressingMode) -> Option<u8> {
+    OPCODES
+        .iter()
+        .position(|opcode| match opcode {
+            Some(opcode) => opcode.name == name && opcode.addr_mode == addr_mode,
+            None => false,
+        })
+        .map(|i| (i & 0xFF) as u8)
+}
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum OpcodeType {
@@ -783,6 +800,27 @@ pub enum OpcodeType {
     TXA,
     TXS,
     TYA,
+    // Undocumented NMOS opcodes, see `undocumented`
+    ALR,
+    ANC,
+    ANE, // Unstable
+    ARR,
+    DCP,
+    ISC,
+    JAM,
+    LAS,
+    LAX,
+    LXA, // Unstable
+    RLA,
+    RRA,
+    SAX,
+    SBX,
+    SHA, // Unstable
+    SHX, // Unstable
+    SHY, // Unstable
+    SLO,
+    SRE,
+    TAS, // Unstable
 }
 impl OpcodeType {
     pub fn identify<'s, S: std::ops::Deref<Target = &'s str>>(
@@ -848,6 +886,18 @@ impl OpcodeType {
             _ => Err(()),
         }
     }
+    /// Read instructions take an extra cycle when indexing crosses a page
+    pub fn has_page_penalty(self) -> bool {
+        use OpcodeType::*;
+        let read_ops = [ADC, AND, CMP, EOR, LDA, LDX, LDY, ORA, SBC, LAS, LAX, NOP];
+        read_ops.contains(&self)
+    }
+    /// Undocumented opcodes whose results change from chip to chip
+    pub fn is_unstable(self) -> bool {
+        use OpcodeType::*;
+        let unstable_ops = [ANE, LXA, SHA, SHX, SHY, TAS];
+        unstable_ops.contains(&self)
+    }
     pub fn is_branch_op(self) -> bool {
         use OpcodeType::*;
         let branch_ops = [BCC, BCS, BEQ, BMI, BNE, BPL, BVC, BVS];
@@ -868,6 +918,65 @@ pub struct OpcodeData {
 pub fn from_code(code: u8) -> Option<OpcodeData> {
     OPCODES[code as usize]
 }
+/// Whether `code` is one of the 151 documented opcodes in `OPCODES`
+pub fn is_documented(code: u8) -> bool {
+    OPCODES[code as usize].is_some()
+}
+/// What the NMOS 6502 does with the 105 opcodes missing from `OPCODES`.
+/// `None` for the documented ones
+pub fn undocumented(code: u8) -> Option<OpcodeData> {
+    use AddressingMode::*;
+    use OpcodeType::*;
+    if is_documented(code) {
+        return None;
+    }
+    let (name, addr_mode) = match code {
+        0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => {
+            (JAM, IMPL)
+        }
+        0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => (NOP, IMPL),
+        0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 => (NOP, IMM),
+        0x04 | 0x44 | 0x64 => (NOP, ZPG),
+        0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 => (NOP, ZPGX),
+        0x0C => (NOP, ABS),
+        0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => (NOP, ABSX),
+        0x0B | 0x2B => (ANC, IMM),
+        0x4B => (ALR, IMM),
+        0x6B => (ARR, IMM),
+        0x8B => (ANE, IMM),
+        0xAB => (LXA, IMM),
+        0xCB => (SBX, IMM),
+        0xEB => (SBC, IMM),
+        0x93 => (SHA, INDY),
+        0x9F => (SHA, ABSY),
+        0x9B => (TAS, ABSY),
+        0x9C => (SHY, ABSX),
+        0x9E => (SHX, ABSY),
+        0xBB => (LAS, ABSY),
+        // Indexing by X would use X to store or load it, so these index by Y
+        0x97 => (SAX, ZPGY),
+        0xB7 => (LAX, ZPGY),
+        0xBF => (LAX, ABSY),
+        // The rest sit in the columns ending in 3, 7 and F, laid out like ORA and friends
+        _ => {
+            let names = [SLO, RLA, SRE, RRA, SAX, LAX, DCP, ISC];
+            let modes = [INDX, ZPG, IMM, ABS, INDY, ZPGX, ABSY, ABSX];
+            (names[code as usize >> 5], modes[(code as usize >> 2) & 7])
+        }
+    };
+    Some(OpcodeData { name, addr_mode })
+}
+/// Undocumented opcodes that every NMOS 6502 runs the same way. JAM isn't one of them,
+/// as it locks the CPU up
+pub fn is_stable(code: u8) -> bool {
+    match undocumented(code) {
+        Some(opcode) => !opcode.name.is_unstable() && opcode.name != OpcodeType::JAM,
+        None => false,
+    }
+}
+pub fn get_cycles(code: u8) -> usize {
+    OP_CYCLES[code as usize] as usize
+}
 
 mod test {
     #[test]
@@ -880,4 +989,25 @@ mod test {
             assert_eq!(res.is_ok(), *is_ok);
         }
     }
+    #[test]
+    fn test_undocumented() {
+        use super::{is_documented, is_stable, undocumented, OpcodeType};
+        use crate::emulator::addressing_modes::AddressingMode;
+        let codes: Vec<u8> = (0..=0xFF).filter(|code| !is_documented(*code)).collect();
+        assert_eq!(codes.len(), 105);
+        for code in codes {
+            assert!(undocumented(code).is_some());
+        }
+        assert!(undocumented(0xA9).is_none());
+        let lax = undocumented(0xB3).unwrap();
+        assert_eq!(lax.name, OpcodeType::LAX);
+        assert_eq!(lax.addr_mode, AddressingMode::INDY);
+        let dcp = undocumented(0xDF).unwrap();
+        assert_eq!(dcp.name, OpcodeType::DCP);
+        assert_eq!(dcp.addr_mode, AddressingMode::ABSX);
+        assert!(is_stable(0xC7));
+        assert!(!is_stable(0x8B)); // ANE
+        assert!(!is_stable(0x02)); // JAM
+        assert_eq!((0..=0xFF).filter(|code| is_stable(*code)).count(), 86);
+    }
 }
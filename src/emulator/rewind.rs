@@ -0,0 +1,144 @@
+use super::error::CpuError;
+use super::snapshot::Snapshot;
+use super::system::System;
+use std::collections::VecDeque;
+
+/// Snapshots taken every `interval` cycles, keeping the last `capacity`.
+/// Going back restores the closest one before the target and runs forward from there,
+/// which lands on the same instruction as long as nothing outside the CPU changed in between,
+/// like the IRQ and NMI lines
+pub struct History {
+    snapshots: VecDeque<Snapshot>,
+    capacity: usize,
+    interval: usize,
+}
+impl History {
+    pub fn new(capacity: usize, interval: usize) -> Self {
+        Self {
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            interval: interval.max(1),
+        }
+    }
+    /// Call before every step. Takes a snapshot when `interval` cycles went by since the last one
+    pub fn record(&mut self, system: &System) {
+        // Anything after the current cycle is from a timeline that was rewound
+        while matches!(self.snapshots.back(), Some(last) if last.cycles > system.cycles) {
+            self.snapshots.pop_back();
+        }
+        let due = match self.snapshots.back() {
+            Some(last) => system.cycles >= last.cycles + self.interval,
+            None => true,
+        };
+        if due {
+            if self.snapshots.len() == self.capacity {
+                self.snapshots.pop_front();
+            }
+            self.snapshots.push_back(system.snapshot());
+        }
+    }
+    /// Forgets everything, for when the system restarts or loads a state
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+    /// The earliest cycle it can go back to
+    pub fn oldest(&self) -> Option<usize> {
+        self.snapshots.front().map(|snapshot| snapshot.cycles)
+    }
+    /// Goes back to the last instruction that started at or before `cycle`,
+    /// or as far as the history goes. `false` if there's nothing to go back to
+    pub fn rewind_to(&mut self, system: &mut System, cycle: usize) -> Result<bool, CpuError> {
+        let snapshot = match self
+            .snapshots
+            .iter()
+            .rev()
+            .find(|snapshot| snapshot.cycles <= cycle)
+            .or_else(|| self.snapshots.front())
+        {
+            Some(snapshot) => snapshot.clone(),
+            None => return Ok(false),
+        };
+        // Replaying shouldn't log instructions twice or report old watchpoint hits
+        let trace = system.trace.take();
+        let result = Self::replay(system, &snapshot, cycle);
+        system.trace = trace;
+        system.take_watch_hit();
+        result
+    }
+    /// Goes back one instruction
+    pub fn step_back(&mut self, system: &mut System) -> Result<bool, CpuError> {
+        match system.cycles.checked_sub(1) {
+            Some(cycle) => self.rewind_to(system, cycle),
+            None => Ok(false),
+        }
+    }
+
+    /// Counts the steps from `snapshot` that stay at or before `cycle`, then runs that many
+    fn replay(system: &mut System, snapshot: &Snapshot, cycle: usize) -> Result<bool, CpuError> {
+        if system.restore(snapshot).is_err() {
+            return Ok(false);
+        }
+        let mut steps = 0;
+        while system.cycles <= cycle {
+            system.step()?;
+            steps += 1;
+        }
+        if system.restore(snapshot).is_err() {
+            return Ok(false);
+        }
+        for _ in 1..steps {
+            system.step()?;
+        }
+        Ok(true)
+    }
+}
+
+mod test {
+    #[test]
+    fn test_rewind() {
+        use super::History;
+        use crate::emulator::addressing_modes::Address;
+        use crate::emulator::system::System;
+        let mut system = System::new();
+        // loop: INX; STX $0200; JMP loop
+        system
+            .bus
+            .load(Address(0x0600), &[0xE8, 0x8E, 0x00, 0x02, 0x4C, 0x00, 0x06]);
+        system.bus.load(Address(0xFFFC), &[0x00, 0x06]);
+        let mut history = History::new(4, 20);
+        let mut states = vec![];
+        for _ in 0..60 {
+            history.record(&system);
+            states.push(system.snapshot());
+            system.step().unwrap();
+        }
+        // Only the last 4 snapshots are kept
+        assert_eq!(history.snapshots.len(), 4);
+
+        let end = system.snapshot();
+        assert!(history.step_back(&mut system).unwrap());
+        assert_eq!(system.snapshot(), states[59]);
+        assert!(history.step_back(&mut system).unwrap());
+        assert_eq!(system.snapshot(), states[58]);
+
+        // Lands on the instruction running at that cycle
+        let target = states[50].cycles + 1;
+        assert!(history.rewind_to(&mut system, target).unwrap());
+        assert_eq!(system.snapshot(), states[50]);
+
+        // Stepping forward again gets to the same place
+        while system.cycles < end.cycles {
+            history.record(&system);
+            system.step().unwrap();
+        }
+        assert_eq!(system.snapshot(), end);
+
+        // Stops at the oldest snapshot
+        let oldest = history.oldest().unwrap();
+        assert!(history.rewind_to(&mut system, 0).unwrap());
+        assert_eq!(system.cycles, oldest);
+
+        history.clear();
+        assert!(!history.step_back(&mut system).unwrap());
+    }
+}
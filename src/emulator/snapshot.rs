@@ -0,0 +1,190 @@
+use super::addressing_modes::Address;
+use super::error::StateError;
+use super::system::IllegalOpcodes;
+use std::path::Path;
+
+/// Bumped whenever the layout changes. Older states keep loading
+pub const VERSION: u16 = 1;
+static MAGIC: &[u8; 8] = b"6502SAVE";
+
+/// Everything needed to pick a run back up: registers, cycle count, interrupt lines
+/// and whatever the bus saves. Breakpoints, watchpoints and the trace aren't included
+///
+/// Stored little endian:
+/// `6502SAVE | version: u16 | cycles: u64 | A X Y S P | PC: u16 | line bits | illegal opcodes |
+/// bus length: u32 | bus`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub cycles: usize,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub p: u8,
+    pub pc: u16,
+    pub bcd: bool,
+    pub irq: bool,
+    pub nmi: bool,
+    pub nmi_pending: bool,
+    pub needs_reset: bool,
+    pub jammed: bool,
+    pub illegal_opcodes: IllegalOpcodes,
+    /// From `Bus::save_state`
+    pub bus: Vec<u8>,
+}
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.cycles as u64).to_le_bytes());
+        bytes.extend_from_slice(&[self.a, self.x, self.y, self.s, self.p]);
+        bytes.extend_from_slice(&self.pc.to_le_bytes());
+        let lines = [
+            self.bcd,
+            self.irq,
+            self.nmi,
+            self.nmi_pending,
+            self.needs_reset,
+            self.jammed,
+        ];
+        bytes.push(
+            lines
+                .iter()
+                .enumerate()
+                .fold(0, |bits, (i, &set)| bits | (set as u8) << i),
+        );
+        bytes.push(match self.illegal_opcodes {
+            IllegalOpcodes::Halt => 0,
+            IllegalOpcodes::Nop => 1,
+            IllegalOpcodes::Emulate => 2,
+        });
+        bytes.extend_from_slice(&(self.bus.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.bus);
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, StateError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(StateError::NotAState);
+        }
+        let mut reader = Reader(&bytes[MAGIC.len()..]);
+        let version = u16::from_le_bytes(reader.array()?);
+        if version > VERSION {
+            return Err(StateError::Version(version));
+        }
+        let cycles = u64::from_le_bytes(reader.array()?) as usize;
+        let [a, x, y, s, p] = reader.array()?;
+        let pc = u16::from_le_bytes(reader.array()?);
+        let [lines] = reader.array()?;
+        let line = |i: u8| lines & (1 << i) != 0;
+        let illegal_opcodes = match reader.array()? {
+            [0] => IllegalOpcodes::Halt,
+            [1] => IllegalOpcodes::Nop,
+            [2] => IllegalOpcodes::Emulate,
+            _ => return Err(StateError::NotAState),
+        };
+        let len = u32::from_le_bytes(reader.array()?) as usize;
+        let bus = reader.take(len)?.to_vec();
+        Ok(Self {
+            cycles,
+            a,
+            x,
+            y,
+            s,
+            p,
+            pc,
+            bcd: line(0),
+            irq: line(1),
+            nmi: line(2),
+            nmi_pending: line(3),
+            needs_reset: line(4),
+            jammed: line(5),
+            illegal_opcodes,
+            bus,
+        })
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), StateError> {
+        std::fs::write(path, self.to_bytes()).map_err(StateError::Io)
+    }
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, StateError> {
+        Self::from_bytes(&std::fs::read(path).map_err(StateError::Io)?)
+    }
+    pub fn pc(&self) -> Address {
+        Address(self.pc as usize)
+    }
+}
+
+/// Reads fields off the front of a state
+struct Reader<'a>(&'a [u8]);
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.0.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+}
+
+mod test {
+    #[test]
+    fn test_snapshot() {
+        use super::{Snapshot, StateError};
+        use crate::emulator::addressing_modes::Address;
+        use crate::emulator::bus::{Memory, MemoryMap};
+        use crate::emulator::system::System;
+        let mut system = System::new();
+        // LDA #$42; STA $0200; INX
+        system
+            .bus
+            .load(Address(0x0600), &[0xA9, 0x42, 0x8D, 0x00, 0x02, 0xE8]);
+        system.bus.load(Address(0xFFFC), &[0x00, 0x06]);
+        system.step().unwrap();
+        // I is set after reset, so the IRQ line just gets saved
+        system.set_irq(true);
+        let saved = system.snapshot();
+        let bytes = saved.to_bytes();
+        assert_eq!(Snapshot::from_bytes(&bytes).unwrap(), saved);
+
+        system.step().unwrap();
+        system.step().unwrap();
+        assert_eq!(system.bus.peek(Address(0x0200)), 0x42);
+        system.restore(&saved).unwrap();
+        assert_eq!(system.bus.peek(Address(0x0200)), 0x00);
+        assert_eq!(system.snapshot(), saved);
+
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(StateError::Truncated)
+        ));
+        assert!(matches!(
+            Snapshot::from_bytes(b"not a state"),
+            Err(StateError::NotAState)
+        ));
+        let mut newer = bytes.clone();
+        newer[8] = 0xFF;
+        assert!(matches!(
+            Snapshot::from_bytes(&newer),
+            Err(StateError::Version(_))
+        ));
+
+        // A state doesn't fit a machine with other devices
+        let mut map = MemoryMap::new(Box::new(Memory::ram(0x10000)));
+        map.map(
+            Address(0x8000),
+            Address(0x80FF),
+            Box::new(Memory::ram(0x100)),
+        );
+        let mut mapped = System::with_bus(Box::new(map));
+        assert!(matches!(mapped.restore(&saved), Err(StateError::Mismatch)));
+        let state = mapped.snapshot();
+        mapped.bus.load(Address(0x8000), &[0x12]);
+        mapped.restore(&state).unwrap();
+        assert_eq!(mapped.bus.peek(Address(0x8000)), 0x00);
+    }
+}
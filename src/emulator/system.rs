@@ -1,25 +1,58 @@
-use super::addressing_modes::{get_size, Address, AddressingMode};
+use super::addressing_modes::{get_size, Address, AddressingMode, Operand};
+use super::bus::Bus;
 use super::components::{Flags, Ram, Registers};
-use super::error;
+use super::debugger::{Access, WatchHit, Watchpoint};
+use super::error::{CpuError, CpuErrorKind, StateError};
 use super::opcodes;
+use super::snapshot::Snapshot;
+use super::trace::Tracer;
 use super::OpcodeType;
+use serde::Deserialize;
 
-fn invalid_mode<T>(mode_used: AddressingMode) -> T {
-    panic!("The addressign mode used ({:?}) is either not valid for this opcode, or expects an argument which was not provided",mode_used)
+/// Records the first access that hits a watchpoint
+fn watch(
+    watchpoints: &[Watchpoint],
+    hit: &mut Option<WatchHit>,
+    addr: Address,
+    access: Access,
+    value: u8,
+) {
+    if hit.is_none() && watchpoints.iter().any(|w| w.matches(addr, access)) {
+        *hit = Some(WatchHit {
+            addr,
+            access,
+            value,
+        });
+    }
 }
 
+static NMI_VEC_ADDR: Address = Address(0xFFFA);
 static RESET_VEC_ADDR: Address = Address(0xFFFC);
+static IRQ_VEC_ADDR: Address = Address(0xFFFE);
 
 macro_rules! fetch {
     ($self:ident PC+$off:expr) => {
-        $self.ram[$self.registers.PC + Address($off)]
-    };
-    ($self:ident $addr:expr) => {
-        $self.ram[$addr]
-    };
-    ($self:ident D $addr:expr) => {
-        ($self.ram[$addr.next()] as u16) << 8 | $self.ram[$addr] as u16
+        $self
+            .bus
+            .read($self.registers.PC.wrapping_add($off as usize))
     };
+    // The 2 byte operand after the opcode, which isn't a data access
+    ($self:ident D PC+1) => {{
+        let lo = fetch!($self PC+1) as u16;
+        (fetch!($self PC+2) as u16) << 8 | lo
+    }};
+    ($self:ident $addr:expr) => {{
+        // Borrows fields one by one, so it works inside calls like `self.registers.set_a(...)`
+        let addr: Address = $addr;
+        let value = $self.bus.read(addr);
+        watch(&$self.watchpoints, &mut $self.watch_hit, addr, Access::Read, value);
+        value
+    }};
+    ($self:ident D $addr:expr) => {{
+        let addr: Address = $addr;
+        let lo = fetch!($self addr) as u16;
+        (fetch!($self addr.next()) as u16) << 8 | lo
+    }};
 }
 macro_rules! operation {
     ($self:ident A+$b:expr) => {
@@ -28,156 +61,722 @@ macro_rules! operation {
     ($self:ident X+$b:expr) => {
         $self.registers.X.wrapping_add($b as u8)
     };
-    (unwrap $arg:ident $addr:ident) => {
-        $arg.unwrap_or_else(|| invalid_mode($addr.addr_mode))
+    ($self:ident Y+$b:expr) => {
+        $self.registers.Y.wrapping_add($b as u8)
     };
+    // Leaves `step` with an error when the operand isn't an address
+    (unwrap $self:ident $operand:ident $opcode:ident $code:ident) => {
+        match $operand {
+            Operand::Address(addr) => addr,
+            _ => {
+                let kind = CpuErrorKind::InvalidMode($code.addr_mode);
+                return Err($self.error($opcode, kind));
+            }
+        }
+    };
+}
+
+/// What `System::step` does with the opcodes left out of the documented set
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IllegalOpcodes {
+    /// Stops with `CpuErrorKind::UnknownOp`
+    #[default]
+    Halt,
+    /// Skips them, taking as many bytes and cycles as the real instruction
+    Nop,
+    /// Runs them like an NMOS 6502. JAM locks the CPU up until a reset,
+    /// and the unstable ones (ANE, LXA, SHA, SHX, SHY and TAS) still stop
+    Emulate,
 }
 
 pub struct System {
+    /// Clock cycles run since the last restart
     pub cycles: usize,
-    pub ram: Ram,
+    pub bus: Box<dyn Bus>,
     pub registers: Registers,
+    /// Set to false to emulate CPUs without decimal mode, like the NES' 2A03.
+    /// The D flag can still be set and cleared, but ADC and SBC ignore it
+    pub bcd: bool,
+    pub illegal_opcodes: IllegalOpcodes,
+    /// Checked on every data access. Instruction fetches and dummy reads don't count
+    pub watchpoints: Vec<Watchpoint>,
+    watch_hit: Option<WatchHit>,
+    /// Logs every instruction when set
+    pub trace: Option<Tracer>,
+    trace_error: Option<std::io::Error>,
+    irq: bool,
+    nmi: bool,
+    nmi_pending: bool,
+    needs_reset: bool,
+    /// Hit a JAM. Only a reset gets the CPU going again
+    jammed: bool,
 }
 impl System {
     pub fn new() -> Self {
+        Self::with_bus(Box::new(Ram::new()))
+    }
+    pub fn with_bus(bus: Box<dyn Bus>) -> Self {
         Self {
             cycles: 0,
-            ram: Ram([0x00; 0x10000]),
+            bus,
             registers: Registers::default(),
+            bcd: true,
+            illegal_opcodes: IllegalOpcodes::default(),
+            watchpoints: vec![],
+            watch_hit: None,
+            trace: None,
+            trace_error: None,
+            irq: false,
+            nmi: false,
+            nmi_pending: false,
+            needs_reset: true,
+            jammed: false,
         }
     }
-    pub fn init(&mut self) -> Result<(), error::CpuError> {
-        let lo: u16 = self.ram[RESET_VEC_ADDR] as u16;
-        let hi: u16 = self.ram[RESET_VEC_ADDR.same_page_add(1usize)] as u16;
+    pub fn init(&mut self) -> Result<(), CpuError> {
+        let lo: u16 = fetch!(self RESET_VEC_ADDR) as u16;
+        let hi: u16 = fetch!(self RESET_VEC_ADDR.same_page_add(1usize)) as u16;
         let addr = hi << 8 | lo;
         self.registers.PC = addr.into();
+        // Reset runs the interrupt sequence with writes disabled:
+        // S is decremented three times, nothing is stored, and I gets set
+        self.registers.S = self.registers.S.wrapping_sub(3);
+        self.registers.set_flag(Flags::Int, true);
+        self.needs_reset = false;
+        self.jammed = false;
+        self.cycles += 7;
         Ok(())
     }
-    pub fn step(&mut self) -> Result<(), error::CpuError> {
-        if self.cycles == 0 {
-            println!("Initializing");
-            self.init()?;
+    /// Pulls the reset line. The reset sequence runs at the start of the next step
+    pub fn reset(&mut self) {
+        self.needs_reset = true;
+    }
+    pub fn needs_reset(&self) -> bool {
+        self.needs_reset
+    }
+    pub fn jammed(&self) -> bool {
+        self.jammed
+    }
+    /// The error for the instruction at PC
+    fn error(&self, opcode: u8, kind: CpuErrorKind) -> CpuError {
+        CpuError {
+            pc: self.registers.PC,
+            opcode,
+            kind,
+        }
+    }
+    fn decimal_enabled(&self) -> bool {
+        self.bcd && self.registers.test(Flags::Decimal)
+    }
+    /* #region Data accesses */
+    fn write(&mut self, addr: Address, value: u8) {
+        self.bus.write(addr, value);
+        watch(
+            &self.watchpoints,
+            &mut self.watch_hit,
+            addr,
+            Access::Write,
+            value,
+        );
+    }
+    /// The first access that hit a watchpoint since the last call
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+    /* #endregion */
+    /* #region Interrupts */
+    /// Drives the IRQ line. While asserted, an interrupt is taken
+    /// before every instruction as long as the I flag is clear
+    pub fn set_irq(&mut self, asserted: bool) {
+        self.irq = asserted;
+    }
+    /// Drives the NMI line. NMI is edge triggered:
+    /// only the transition from released to asserted causes an interrupt
+    pub fn set_nmi(&mut self, asserted: bool) {
+        if asserted && !self.nmi {
+            self.nmi_pending = true;
         }
-        /* if self.registers.test(Flags::Break) {
-            return Err(error::EmulatorError::Break);
-        } */
-        println!("Step on {:04X}", *self.registers.PC);
-        let code = self.ram[self.registers.PC];
-        let code = match opcodes::from_code(code) {
-            None => return Err(error::CpuError::UnknownOp(code)),
-            Some(v) => v,
+        self.nmi = asserted;
+    }
+    pub fn irq(&self) -> bool {
+        self.irq
+    }
+    pub fn nmi(&self) -> bool {
+        self.nmi
+    }
+    /// Pushes PC and the status, then jumps through `vector`.
+    /// B is only set on the pushed status when the interrupt comes from BRK
+    fn interrupt(&mut self, vector: Address, return_addr: Address, brk: bool) {
+        self.push_address(return_addr);
+        let flags = self.registers.flags | Flags::AlwaysOne as u8;
+        let flags = if brk {
+            flags | Flags::Break as u8
+        } else {
+            flags & !(Flags::Break as u8)
         };
-        println!(" Opcode {:?}", code.name);
-        let arg: Option<u16> = match code.addr_mode {
-            AddressingMode::IMPL => None, // No argument
-            AddressingMode::A => None,    // No argument
-            AddressingMode::IMM => {
-                // Next byte is the argument
-                let addr = fetch!(self PC+1);
-                Some(addr as u16)
-            }
+        self.push(flags);
+        self.registers.set_flag(Flags::Int, true);
+        self.registers.PC = Address(fetch!(self D vector) as usize);
+    }
+    /// Services a pending interrupt, if any. NMI has priority over IRQ.
+    /// Takes 7 cycles, like BRK
+    fn poll_interrupts(&mut self) -> bool {
+        let vector = if self.nmi_pending {
+            self.nmi_pending = false;
+            NMI_VEC_ADDR
+        } else if self.irq && !self.registers.test(Flags::Int) {
+            IRQ_VEC_ADDR
+        } else {
+            return false;
+        };
+        self.interrupt(vector, self.registers.PC, false);
+        self.cycles += 7;
+        true
+    }
+    /* #endregion */
+    /* #region Stack */
+    /// Pushes a byte to the stack at page $01
+    pub fn push(&mut self, value: u8) {
+        self.write(self.registers.stack_addr(), value);
+        self.registers.S = self.registers.S.wrapping_sub(1);
+    }
+    /// Pulls a byte from the stack at page $01
+    pub fn pop(&mut self) -> u8 {
+        self.registers.S = self.registers.S.wrapping_add(1);
+        fetch!(self self.registers.stack_addr())
+    }
+    /// Pushes an address, high byte first
+    pub fn push_address(&mut self, addr: Address) {
+        self.push((*addr >> 8) as u8);
+        self.push(*addr as u8);
+    }
+    /// Pulls an address, low byte first
+    pub fn pop_address(&mut self) -> Address {
+        let lo = self.pop() as usize;
+        let hi = self.pop() as usize;
+        Address(hi << 8 | lo)
+    }
+    /* #endregion */
+    /// Computes the operand of the instruction at PC according to `mode`.
+    /// Memory modes resolve to the effective address, without reading from it.
+    /// Also tells if indexing (or a branch) crossed a page boundary
+    pub fn resolve_operand(&mut self, mode: AddressingMode) -> (Operand, bool) {
+        self.resolve(mode, false)
+    }
+    /// `resolve_operand` for an instruction that may be `skipped`, whose pointers
+    /// get read without counting as data accesses
+    fn resolve(&mut self, mode: AddressingMode, skipped: bool) -> (Operand, bool) {
+        macro_rules! pointer {
+            ($addr:expr) => {{
+                let addr: Address = $addr;
+                if skipped {
+                    self.bus.dummy_read(addr);
+                    self.bus.peek(addr)
+                } else {
+                    fetch!(self addr)
+                }
+            }};
+        }
+        let crossed = |base: Address, addr: Address| *base & 0xFF00 != *addr & 0xFF00;
+        let operand = match mode {
+            AddressingMode::IMPL => Operand::None, // No argument
+            AddressingMode::A => Operand::Accumulator,
+            // Next byte is the argument
+            AddressingMode::IMM => Operand::Immediate(fetch!(self PC+1)),
             AddressingMode::ABS => {
-                // Next 2 bytes are an address from where to fetch the real argument
-                let addr = self.registers.PC.next();
-                Some(fetch!(self D addr) as u16)
+                // Next 2 bytes are the address
+                Operand::Address(Address(fetch!(self D PC+1) as usize))
+            }
+            AddressingMode::ABSX => {
+                // Same as ABS, but X is added to the address
+                let base = Address(fetch!(self D PC+1) as usize);
+                let addr = base.wrapping_add(self.registers.X);
+                return (Operand::Address(addr), crossed(base, addr));
+            }
+            AddressingMode::ABSY => {
+                // Same as ABS, but Y is added to the address
+                let base = Address(fetch!(self D PC+1) as usize);
+                let addr = base.wrapping_add(self.registers.Y);
+                return (Operand::Address(addr), crossed(base, addr));
             }
             AddressingMode::ZPG => {
                 // Next byte is an address from the range 0x0000-0x00FF
-                let addr = self.registers.PC.same_page_add(1u16);
-                Some(fetch!(self addr) as u16)
+                Operand::Address(fetch!(self PC+1).into())
+            }
+            AddressingMode::ZPGX => {
+                // Same as ZPG, but X is added to the address without leaving page zero.
+                // The CPU reads from the unindexed address while adding
+                let arg = fetch!(self PC+1);
+                self.bus.dummy_read(arg.into());
+                Operand::Address(operation!(self X+arg).into())
+            }
+            AddressingMode::ZPGY => {
+                // Same as ZPG, but Y is added to the address without leaving page zero
+                let arg = fetch!(self PC+1);
+                self.bus.dummy_read(arg.into());
+                Operand::Address(operation!(self Y+arg).into())
+            }
+            AddressingMode::IND => {
+                // Next 2 bytes are the address of the real address.
+                // The NMOS 6502 doesn't carry into the high byte when fetching it,
+                // so JMP ($10FF) reads from $10FF and $1000
+                let ptr = Address(fetch!(self D PC+1) as usize);
+                let lo = fetch!(self ptr) as usize;
+                let hi = fetch!(self ptr.same_page_add(1usize)) as usize;
+                Operand::Address(Address(hi << 8 | lo))
             }
             AddressingMode::INDX => {
                 // Take the next byte and add it to X,
                 // then use the result as an address and fetch 2 bytes
                 let arg = fetch!(self PC+1); // Opcode arg
-                let addr: Address = operation!(self X+arg).into(); // Zero-page addr
-                let addr_lo = self.ram[addr] as usize;
-                let addr_hi = self.ram[addr.next()] as usize;
-                let res_addr = addr_hi << 8 | addr_lo;
-                Some(res_addr as u16)
+                self.bus.dummy_read(arg.into()); // Read while adding X, like ZPGX
+                let ptr: Address = operation!(self X+arg).into(); // Zero-page addr
+                let lo = pointer!(ptr) as usize;
+                let hi = pointer!(ptr.same_page_add(1usize)) as usize;
+                Operand::Address(Address(hi << 8 | lo))
+            }
+            AddressingMode::INDY => {
+                // Take the next byte as a zero-page address, fetch 2 bytes from it
+                // and add Y to the result
+                let ptr: Address = fetch!(self PC+1).into(); // Zero-page addr
+                let lo = pointer!(ptr) as usize;
+                let hi = pointer!(ptr.same_page_add(1usize)) as usize;
+                let base = Address(hi << 8 | lo);
+                let addr = base.wrapping_add(self.registers.Y);
+                return (Operand::Address(addr), crossed(base, addr));
             }
             AddressingMode::REL => {
-                // Add PC with the next byte
-                let arg = fetch!(self PC+1) as u8 as i8 as isize;
-                let pc = (*self.registers.PC) as isize;
-                let new_pc = (arg + pc) & 0xFFFF;
-                Some(new_pc as u16)
+                // Add the address of the next instruction with the next byte
+                let offset = fetch!(self PC+1) as i8 as isize;
+                let pc = self.registers.PC.wrapping_add(get_size(mode));
+                let target = Address(((*pc as isize + offset) & 0xFFFF) as usize);
+                return (Operand::Address(target), crossed(pc, target));
             }
-            _ => {
-                unimplemented!("Unimplemented addressing mode {:?}", code.addr_mode);
+        };
+        (operand, false)
+    }
+    /// Reproduces the reads the CPU throws away while decoding an instruction
+    fn dummy_reads(&mut self, code: opcodes::OpcodeData, operand: Operand, page_crossed: bool) {
+        let index = match code.addr_mode {
+            AddressingMode::ABSX => self.registers.X,
+            AddressingMode::ABSY | AddressingMode::INDY => self.registers.Y,
+            AddressingMode::IMPL | AddressingMode::A => {
+                // One byte instructions still read the byte after the opcode
+                self.bus.dummy_read(self.registers.PC.wrapping_add(1usize));
+                // Pulling spends a cycle incrementing S, reading the top of the stack meanwhile
+                let pulls = [
+                    OpcodeType::PLA,
+                    OpcodeType::PLP,
+                    OpcodeType::RTS,
+                    OpcodeType::RTI,
+                ];
+                if pulls.contains(&code.name) {
+                    self.bus.dummy_read(self.registers.stack_addr());
+                }
+                return;
             }
+            _ => return,
         };
-        let mut branch_taken = false; // Don't update PC if we take a branch
-        println!(" Argument: {:#04X?}", arg);
-        match code.name {
-            OpcodeType::BRK => {
-                println!("Stepped on break. Ending");
-                println!("{:#?}", self.registers);
-                /* return Err(error::EmulatorError::Break); */
-            }
-            OpcodeType::NOP => {}
-            OpcodeType::LDA => match code.addr_mode {
-                AddressingMode::IMM => self.registers.set_a(operation!(unwrap arg code) as u8),
-                AddressingMode::ABS => self
-                    .registers
-                    .set_a(fetch!(self operation!(unwrap arg code).into())),
-                AddressingMode::ZPG => self
-                    .registers
-                    .set_a(fetch!(self operation!(unwrap arg code).into())),
-                _ => panic!("Invalid addressing mode for {:?}", code.name),
+        // Indexing first reads from the address before its high byte gets fixed.
+        // Reads skip it when no page was crossed, but writes always do it
+        if let Operand::Address(addr) = operand {
+            if page_crossed || !code.name.has_page_penalty() {
+                let hi = (*addr + 0x10000 - index as usize) & 0xFF00;
+                self.bus.dummy_read(Address(hi | *addr & 0x00FF));
+            }
+        }
+    }
+    pub fn step(&mut self) -> Result<(), CpuError> {
+        if self.needs_reset {
+            self.init()?;
+        }
+        // The clock keeps running, but nothing else happens. Not even interrupts
+        if self.jammed {
+            self.cycles += 1;
+            return Ok(());
+        }
+        if self.poll_interrupts() {
+            return Ok(());
+        }
+        if let Some(trace) = &mut self.trace {
+            if let Err(e) = trace.log(&*self.bus, &self.registers, self.cycles) {
+                self.stop_trace(e);
+            }
+        }
+        let opcode = fetch!(self PC+0);
+        // `skipped` for undocumented opcodes skipped by `IllegalOpcodes::Nop`.
+        // Their timing still comes from the real instruction, only what it does is dropped
+        let (code, skipped) = match opcodes::from_code(opcode) {
+            Some(v) => (v, false),
+            None => match (self.illegal_opcodes, opcodes::undocumented(opcode)) {
+                (IllegalOpcodes::Nop, Some(code)) => (code, true),
+                (IllegalOpcodes::Emulate, Some(code)) if code.name.is_unstable() => {
+                    return Err(self.error(opcode, CpuErrorKind::UnstableOp))
+                }
+                (IllegalOpcodes::Emulate, Some(code)) => (code, false),
+                _ => return Err(self.error(opcode, CpuErrorKind::UnknownOp)),
             },
-            OpcodeType::STA => match code.addr_mode {
-                AddressingMode::ABS => {
-                    self.ram[operation!(unwrap arg code).into()] = self.registers.A
+        };
+        let (operand, page_crossed) = match code.name {
+            // Reads its operand in its own order, see below
+            OpcodeType::JSR => (Operand::None, false),
+            _ => self.resolve(code.addr_mode, skipped),
+        };
+        let mut cycles = opcodes::get_cycles(opcode);
+        if page_crossed && code.name.has_page_penalty() {
+            cycles += 1;
+        }
+        self.dummy_reads(code, operand, page_crossed);
+        let mut pc_changed = false; // Don't update PC if we jump or take a branch
+        macro_rules! read {
+            () => {
+                match operand {
+                    Operand::Accumulator => self.registers.A,
+                    Operand::Immediate(value) => value,
+                    Operand::Address(addr) => fetch!(self addr),
+                    Operand::None => {
+                        let kind = CpuErrorKind::InvalidMode(code.addr_mode);
+                        return Err(self.error(opcode, kind));
+                    }
                 }
-                AddressingMode::ZPG => {
-                    self.ram[operation!(unwrap arg code).into()] = self.registers.A
+            };
+        }
+        macro_rules! write {
+            ($value:expr) => {{
+                let value: u8 = $value;
+                match operand {
+                    Operand::Accumulator => self.registers.A = value,
+                    Operand::Address(addr) => self.write(addr, value),
+                    _ => {
+                        let kind = CpuErrorKind::InvalidMode(code.addr_mode);
+                        return Err(self.error(opcode, kind));
+                    }
                 }
-                AddressingMode::INDX => {
-                    self.ram[operation!(unwrap arg code).into()] = self.registers.A
+            }};
+        }
+        macro_rules! modify {
+            (|$value:ident| $body:expr) => {{
+                let $value = read!();
+                // NMOS CPUs write the unmodified value back before the result
+                if let Operand::Address(addr) = operand {
+                    self.write(addr, $value);
                 }
-                _ => panic!("Invalid addressing mode for {:?}", code.name),
-            },
-            OpcodeType::ADC => match code.addr_mode {
-                AddressingMode::IMM => self.registers.add_a(operation!(unwrap arg code) as u8),
-                _ => panic!("Invalid addressing mode for {:?}", code.name),
-            },
-            OpcodeType::JMP => match code.addr_mode {
-                AddressingMode::ABS => self.registers.PC = operation!(unwrap arg code).into(),
-                _ => panic!("Invalid addressing mode for {:?}", code.name),
-            },
-            OpcodeType::BEQ if code.addr_mode == AddressingMode::REL => {
-                if self.registers.test(Flags::Zero) {
-                    self.registers.PC = operation!(unwrap arg code).into();
-                    branch_taken = true;
+                let result: u8 = $body;
+                write!(result);
+            }};
+        }
+        macro_rules! branch {
+            ($flag:expr, $status:expr) => {
+                if self.registers.test($flag) == $status {
+                    let target = operation!(unwrap self operand opcode code);
+                    // The CPU reads the next instruction while adding the offset,
+                    // then from the target with PC's old high byte while fixing it
+                    let next = self.registers.PC.wrapping_add(get_size(code.addr_mode));
+                    self.bus.dummy_read(next);
+                    if page_crossed {
+                        self.bus.dummy_read(Address(*next & 0xFF00 | *target & 0x00FF));
+                    }
+                    self.registers.PC = target;
+                    pc_changed = true;
+                    // One more cycle to take the branch, and another to fix PC's high byte
+                    cycles += if page_crossed { 2 } else { 1 };
                 }
+            };
+        }
+        let name = if skipped { OpcodeType::NOP } else { code.name };
+        match name {
+            /* #region Load/Store */
+            OpcodeType::LDA => self.registers.set_a(read!()),
+            OpcodeType::LDX => self.registers.set_x(read!()),
+            OpcodeType::LDY => self.registers.set_y(read!()),
+            OpcodeType::STA => write!(self.registers.A),
+            OpcodeType::STX => write!(self.registers.X),
+            OpcodeType::STY => write!(self.registers.Y),
+            /* #endregion */
+            /* #region Transfers */
+            OpcodeType::TAX => self.registers.set_x(self.registers.A),
+            OpcodeType::TAY => self.registers.set_y(self.registers.A),
+            OpcodeType::TSX => self.registers.set_x(self.registers.S),
+            OpcodeType::TXA => self.registers.set_a(self.registers.X),
+            OpcodeType::TXS => self.registers.S = self.registers.X, // TXS doesn't touch flags
+            OpcodeType::TYA => self.registers.set_a(self.registers.Y),
+            /* #endregion */
+            /* #region Stack */
+            OpcodeType::PHA => self.push(self.registers.A),
+            OpcodeType::PHP => {
+                // B and bit 5 are always set on the pushed copy
+                let flags = self.registers.flags | Flags::Break as u8 | Flags::AlwaysOne as u8;
+                self.push(flags);
+            }
+            OpcodeType::PLA => {
+                let value = self.pop();
+                self.registers.set_a(value);
             }
-            OpcodeType::BNE if code.addr_mode == AddressingMode::REL => {
-                if !self.registers.test(Flags::Zero) {
-                    self.registers.PC = operation!(unwrap arg code).into();
-                    branch_taken = true;
+            OpcodeType::PLP => {
+                let flags = self.pop();
+                self.registers.flags = (flags & !(Flags::Break as u8)) | Flags::AlwaysOne as u8;
+            }
+            /* #endregion */
+            /* #region Arithmetic and logic */
+            OpcodeType::ADC if self.decimal_enabled() => self.registers.add_a_decimal(read!()),
+            OpcodeType::ADC => self.registers.add_a(read!()),
+            OpcodeType::SBC if self.decimal_enabled() => self.registers.sub_a_decimal(read!()),
+            OpcodeType::SBC => self.registers.sub_a(read!()),
+            OpcodeType::AND => self.registers.set_a(self.registers.A & read!()),
+            OpcodeType::ORA => self.registers.set_a(self.registers.A | read!()),
+            OpcodeType::EOR => self.registers.set_a(self.registers.A ^ read!()),
+            OpcodeType::BIT => {
+                let value = read!();
+                self.registers
+                    .set_flag(Flags::Zero, self.registers.A & value == 0);
+                self.registers
+                    .set_flag(Flags::Negative, value & Flags::Negative as u8 != 0);
+                self.registers
+                    .set_flag(Flags::Overflow, value & Flags::Overflow as u8 != 0);
+            }
+            OpcodeType::CMP => self.registers.compare(self.registers.A, read!()),
+            OpcodeType::CPX => self.registers.compare(self.registers.X, read!()),
+            OpcodeType::CPY => self.registers.compare(self.registers.Y, read!()),
+            /* #endregion */
+            /* #region Increments and decrements */
+            OpcodeType::INC => modify!(|value| self.registers.inc(value)),
+            OpcodeType::DEC => modify!(|value| self.registers.dec(value)),
+            OpcodeType::INX => self.registers.set_x(self.registers.X.wrapping_add(1)),
+            OpcodeType::INY => self.registers.set_y(self.registers.Y.wrapping_add(1)),
+            OpcodeType::DEX => self.registers.set_x(self.registers.X.wrapping_sub(1)),
+            OpcodeType::DEY => self.registers.set_y(self.registers.Y.wrapping_sub(1)),
+            /* #endregion */
+            /* #region Shifts and rotates */
+            OpcodeType::ASL => modify!(|value| self.registers.asl(value)),
+            OpcodeType::LSR => modify!(|value| self.registers.lsr(value)),
+            OpcodeType::ROL => modify!(|value| self.registers.rol(value)),
+            OpcodeType::ROR => modify!(|value| self.registers.ror(value)),
+            /* #endregion */
+            /* #region Jumps and calls */
+            OpcodeType::JMP => {
+                self.registers.PC = operation!(unwrap self operand opcode code);
+                pc_changed = true;
+            }
+            OpcodeType::JSR => {
+                // The high byte of the address is read after pushing PC,
+                // so a JSR on the stack page can jump somewhere it just wrote
+                let lo = fetch!(self PC+1) as usize;
+                self.bus.dummy_read(self.registers.stack_addr());
+                // The pushed address is the last byte of the JSR instruction
+                let ret = self.registers.PC.wrapping_add(2usize);
+                self.push_address(ret);
+                let hi = fetch!(self PC+2) as usize;
+                self.registers.PC = Address(hi << 8 | lo);
+                pc_changed = true;
+            }
+            OpcodeType::RTS => {
+                // Reads the pulled address before incrementing it
+                let ret = self.pop_address();
+                self.bus.dummy_read(ret);
+                self.registers.PC = ret.next();
+                pc_changed = true;
+            }
+            OpcodeType::RTI => {
+                let flags = self.pop();
+                self.registers.flags = (flags & !(Flags::Break as u8)) | Flags::AlwaysOne as u8;
+                self.registers.PC = self.pop_address();
+                pc_changed = true;
+            }
+            OpcodeType::BRK => {
+                // BRK skips a padding byte, so the return address is PC+2
+                let ret = self.registers.PC.wrapping_add(2usize);
+                self.interrupt(IRQ_VEC_ADDR, ret, true);
+                pc_changed = true;
+            }
+            /* #endregion */
+            /* #region Branches */
+            OpcodeType::BCC => branch!(Flags::Carry, false),
+            OpcodeType::BCS => branch!(Flags::Carry, true),
+            OpcodeType::BNE => branch!(Flags::Zero, false),
+            OpcodeType::BEQ => branch!(Flags::Zero, true),
+            OpcodeType::BPL => branch!(Flags::Negative, false),
+            OpcodeType::BMI => branch!(Flags::Negative, true),
+            OpcodeType::BVC => branch!(Flags::Overflow, false),
+            OpcodeType::BVS => branch!(Flags::Overflow, true),
+            /* #endregion */
+            /* #region Flags */
+            OpcodeType::CLC => self.registers.set_flag(Flags::Carry, false),
+            OpcodeType::CLD => self.registers.set_flag(Flags::Decimal, false),
+            OpcodeType::CLI => self.registers.set_flag(Flags::Int, false),
+            OpcodeType::CLV => self.registers.set_flag(Flags::Overflow, false),
+            OpcodeType::SEC => self.registers.set_flag(Flags::Carry, true),
+            OpcodeType::SED => self.registers.set_flag(Flags::Decimal, true),
+            OpcodeType::SEI => self.registers.set_flag(Flags::Int, true),
+            /* #endregion */
+            /* #region Undocumented */
+            OpcodeType::SLO => modify!(|value| {
+                let result = self.registers.asl(value);
+                self.registers.set_a(self.registers.A | result);
+                result
+            }),
+            OpcodeType::RLA => modify!(|value| {
+                let result = self.registers.rol(value);
+                self.registers.set_a(self.registers.A & result);
+                result
+            }),
+            OpcodeType::SRE => modify!(|value| {
+                let result = self.registers.lsr(value);
+                self.registers.set_a(self.registers.A ^ result);
+                result
+            }),
+            OpcodeType::RRA => modify!(|value| {
+                let result = self.registers.ror(value);
+                if self.decimal_enabled() {
+                    self.registers.add_a_decimal(result);
+                } else {
+                    self.registers.add_a(result);
+                }
+                result
+            }),
+            OpcodeType::DCP => modify!(|value| {
+                let result = value.wrapping_sub(1);
+                self.registers.compare(self.registers.A, result);
+                result
+            }),
+            OpcodeType::ISC => modify!(|value| {
+                let result = value.wrapping_add(1);
+                if self.decimal_enabled() {
+                    self.registers.sub_a_decimal(result);
+                } else {
+                    self.registers.sub_a(result);
                 }
+                result
+            }),
+            OpcodeType::SAX => write!(self.registers.A & self.registers.X),
+            OpcodeType::LAX => {
+                let value = read!();
+                self.registers.set_a(value);
+                self.registers.set_x(value);
             }
-            _ => {
-                unimplemented!(
-                    "Unimplemented opcode {:?} with {:?}",
-                    code.name,
-                    code.addr_mode
-                );
+            OpcodeType::LAS => {
+                let value = read!() & self.registers.S;
+                self.registers.S = value;
+                self.registers.set_a(value);
+                self.registers.set_x(value);
+            }
+            OpcodeType::ANC => {
+                self.registers.set_a(self.registers.A & read!());
+                let negative = self.registers.test(Flags::Negative);
+                self.registers.set_flag(Flags::Carry, negative);
+            }
+            OpcodeType::ALR => {
+                let value = self.registers.A & read!();
+                let result = self.registers.lsr(value);
+                self.registers.set_a(result);
+            }
+            OpcodeType::ARR if self.decimal_enabled() => self.registers.arr_decimal(read!()),
+            OpcodeType::ARR => self.registers.arr(read!()),
+            OpcodeType::SBX => {
+                let value = read!();
+                let ax = self.registers.A & self.registers.X;
+                self.registers.set_flag(Flags::Carry, ax >= value);
+                self.registers.set_x(ax.wrapping_sub(value));
             }
+            OpcodeType::JAM => {
+                self.jammed = true;
+                pc_changed = true;
+            }
+            // Turned away while decoding
+            OpcodeType::ANE
+            | OpcodeType::LXA
+            | OpcodeType::SHA
+            | OpcodeType::SHX
+            | OpcodeType::SHY
+            | OpcodeType::TAS => {}
+            /* #endregion */
+            OpcodeType::NOP => match operand {
+                // Skipped opcodes keep the bus timing, but don't count as reading anything
+                Operand::Address(addr) if skipped => self.bus.dummy_read(addr),
+                // The undocumented NOPs with an address read from it
+                Operand::Address(_) => {
+                    read!();
+                }
+                _ => {}
+            },
         }
-        if branch_taken || code.name == OpcodeType::JMP {
-            println!("Don't increment PC");
-        } else {
-            self.registers.PC = self.registers.PC + get_size(code.addr_mode).into();
+        if !pc_changed {
+            self.registers.PC = self.registers.PC.wrapping_add(get_size(code.addr_mode));
         }
-        self.cycles += 1;
+        self.cycles += cycles;
+        Ok(())
+    }
+    /// Writes out the buffered trace, to read it while the CPU is stopped
+    pub fn flush_trace(&mut self) {
+        if let Some(trace) = &mut self.trace {
+            if let Err(e) = trace.flush() {
+                self.stop_trace(e);
+            }
+        }
+    }
+    /// Writing the trace failed, so there's no point in going on with it
+    fn stop_trace(&mut self, error: std::io::Error) {
+        self.trace = None;
+        self.trace_error = Some(error);
+    }
+    /// Why tracing stopped on its own, since the last call
+    pub fn take_trace_error(&mut self) -> Option<std::io::Error> {
+        self.trace_error.take()
+    }
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            cycles: self.cycles,
+            a: self.registers.A,
+            x: self.registers.X,
+            y: self.registers.Y,
+            s: self.registers.S,
+            p: self.registers.flags,
+            pc: *self.registers.PC as u16,
+            bcd: self.bcd,
+            irq: self.irq,
+            nmi: self.nmi,
+            nmi_pending: self.nmi_pending,
+            needs_reset: self.needs_reset,
+            jammed: self.jammed,
+            illegal_opcodes: self.illegal_opcodes,
+            bus: self.bus.save_state(),
+        }
+    }
+    /// Goes back to `snapshot`. Nothing changes if the bus doesn't take its state
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), StateError> {
+        self.bus.load_state(&snapshot.bus)?;
+        self.cycles = snapshot.cycles;
+        self.registers.A = snapshot.a;
+        self.registers.X = snapshot.x;
+        self.registers.Y = snapshot.y;
+        self.registers.S = snapshot.s;
+        self.registers.flags = snapshot.p;
+        self.registers.PC = snapshot.pc();
+        self.bcd = snapshot.bcd;
+        self.irq = snapshot.irq;
+        self.nmi = snapshot.nmi;
+        self.nmi_pending = snapshot.nmi_pending;
+        self.needs_reset = snapshot.needs_reset;
+        self.jammed = snapshot.jammed;
+        self.illegal_opcodes = snapshot.illegal_opcodes;
+        self.watch_hit = None;
         Ok(())
     }
     pub fn restart(&mut self) {
         self.cycles = 0;
-        self.ram.load([0x00; 0x10000]);
+        self.needs_reset = true;
+        self.bus.reset();
         self.registers = Registers::default();
+        self.irq = false;
+        self.nmi = false;
+        self.nmi_pending = false;
+        self.jammed = false;
+        self.watch_hit = None;
+    }
+}
+impl std::default::Default for System {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -201,4 +800,350 @@ mod test {
         assert_eq!(cpu.test(Flags::Negative), false);
         assert_eq!(cpu.test(Flags::Int), true);
     }
+    #[test]
+    fn test_loop() {
+        use super::{Address, System};
+        let mut system = System::new();
+        let program = [
+            0xA2, 0x05, // LDX #$05
+            0xA9, 0x00, // LDA #$00
+            0x18, // CLC
+            0x69, 0x03, // ADC #$03
+            0xCA, // DEX
+            0xD0, 0xFA, // BNE $0604
+            0x00, // BRK
+        ];
+        system.bus.load(Address(0x0600), &program);
+        system.bus.write(Address(0xFFFC), 0x00);
+        system.bus.write(Address(0xFFFD), 0x06);
+        while system.registers.PC != Address(0x060A) {
+            system.step().unwrap();
+        }
+        assert_eq!(system.registers.A, 15);
+        assert_eq!(system.registers.X, 0);
+        assert!(!system.registers.test(super::Flags::Carry));
+    }
+    #[test]
+    fn test_subroutine_loop() {
+        use super::{Address, System};
+        let mut system = System::new();
+        let program = [
+            0xA2, 0x05, // LDX #$05
+            0xA9, 0x00, // LDA #$00
+            0x20, 0x10, 0x06, // JSR $0610
+            0xCA, // DEX
+            0xD0, 0xFA, // BNE $0604
+            0x00, // BRK
+        ];
+        let subroutine = [
+            0x18, // CLC
+            0x69, 0x03, // ADC #$03
+            0x60, // RTS
+        ];
+        system.bus.load(Address(0x0600), &program);
+        system.bus.load(Address(0x0610), &subroutine);
+        system.bus.write(Address(0xFFFC), 0x00);
+        system.bus.write(Address(0xFFFD), 0x06);
+        while system.registers.PC != Address(0x060A) {
+            system.step().unwrap();
+        }
+        assert_eq!(system.registers.A, 15);
+        assert_eq!(system.registers.X, 0);
+        assert_eq!(system.registers.S, 0xFD);
+    }
+    #[test]
+    fn test_operand_wraparound() {
+        use super::{Address, AddressingMode, Operand, System};
+        let mut system = System::new();
+        system.registers.PC = Address(0x0600);
+        system.registers.X = 0x10;
+        system.registers.Y = 0x20;
+        system.bus.write(Address(0x0601), 0xF8);
+        system.bus.write(Address(0x0602), 0xFF);
+        // $F8 + X stays in page zero
+        assert_eq!(
+            system.resolve_operand(AddressingMode::ZPGX),
+            (Operand::Address(Address(0x0008)), false)
+        );
+        // $FFF8 + Y wraps around the address space
+        assert_eq!(
+            system.resolve_operand(AddressingMode::ABSY),
+            (Operand::Address(Address(0x0018)), true)
+        );
+        // JMP ($10FF) fetches its high byte from $1000, not $1100
+        system.bus.write(Address(0x0601), 0xFF);
+        system.bus.write(Address(0x0602), 0x10);
+        system.bus.write(Address(0x10FF), 0x34);
+        system.bus.write(Address(0x1000), 0x12);
+        system.bus.write(Address(0x1100), 0x56);
+        assert_eq!(
+            system.resolve_operand(AddressingMode::IND),
+            (Operand::Address(Address(0x1234)), false)
+        );
+        // ($FF),Y takes its high byte from $00
+        system.bus.write(Address(0x00FF), 0xF0);
+        system.bus.write(Address(0x0000), 0x20);
+        assert_eq!(
+            system.resolve_operand(AddressingMode::INDY),
+            (Operand::Address(Address(0x2110)), true)
+        );
+    }
+    #[test]
+    fn test_stack_wraparound() {
+        use super::{Address, System};
+        let mut system = System::new();
+        system.registers.S = 0x00;
+        system.push(0xAB);
+        assert_eq!(system.registers.S, 0xFF);
+        assert_eq!(system.bus.peek(Address(0x0100)), 0xAB);
+        system.push_address(Address(0x1234));
+        assert_eq!(system.bus.peek(Address(0x01FF)), 0x12);
+        assert_eq!(system.bus.peek(Address(0x01FE)), 0x34);
+        assert_eq!(system.pop_address(), Address(0x1234));
+        assert_eq!(system.pop(), 0xAB);
+        assert_eq!(system.registers.S, 0x00);
+    }
+    #[test]
+    fn test_interrupts() {
+        use super::{Address, Flags, System};
+        let mut system = System::new();
+        system.bus.write(Address(0x0600), 0xEA); // NOP
+        system.bus.write(Address(0x0601), 0x58); // CLI
+        system.bus.write(Address(0x0602), 0xEA); // NOP
+        system
+            .bus
+            .load(Address(0xFFFA), &[0x00, 0x80, 0x00, 0x06, 0x00, 0x90]);
+        system.step().unwrap(); // Reset and NOP, I is set by the reset
+        system.set_irq(true);
+        system.step().unwrap(); // IRQ is masked, CLI runs
+        assert_eq!(system.registers.PC, Address(0x0602));
+        system.set_nmi(true);
+        system.step().unwrap(); // NMI wins over IRQ
+        assert_eq!(system.registers.PC, Address(0x8000));
+        assert_eq!(system.pop() & Flags::Break as u8, 0);
+        assert_eq!(system.pop_address(), Address(0x0602));
+        system.registers.set_flag(Flags::Int, false);
+        system.step().unwrap(); // NMI is still held but only its edge counts, so the IRQ is taken
+        assert_eq!(system.registers.PC, Address(0x9000));
+    }
+    #[test]
+    fn test_decimal_mode() {
+        use super::{Flags, Registers};
+        let mut cpu = Registers::default();
+        // (A, operand, carry in) => (A, carry out)
+        let adc = [
+            ((0x58, 0x46, true), (0x05, true)),
+            ((0x12, 0x34, false), (0x46, false)),
+            ((0x81, 0x92, false), (0x73, true)),
+            ((0x99, 0x01, false), (0x00, true)),
+        ];
+        for &((a, n, c), (res, carry)) in adc.iter() {
+            cpu.A = a;
+            cpu.set_flag(Flags::Carry, c);
+            cpu.add_a_decimal(n);
+            assert_eq!((cpu.A, cpu.test(Flags::Carry)), (res, carry));
+        }
+        // 99 + 01 is 00 in decimal, but Z comes from the binary result
+        assert_eq!(cpu.test(Flags::Zero), false);
+        let sbc = [
+            ((0x46, 0x12, true), (0x34, true)),
+            ((0x40, 0x13, true), (0x27, true)),
+            ((0x32, 0x02, false), (0x29, true)),
+            ((0x12, 0x21, true), (0x91, false)),
+        ];
+        for &((a, n, c), (res, carry)) in sbc.iter() {
+            cpu.A = a;
+            cpu.set_flag(Flags::Carry, c);
+            cpu.sub_a_decimal(n);
+            assert_eq!((cpu.A, cpu.test(Flags::Carry)), (res, carry));
+        }
+        // Binary overflow: two positives giving a negative
+        cpu.A = 0x50;
+        cpu.set_flag(Flags::Carry, false);
+        cpu.add_a(0x50);
+        assert_eq!((cpu.A, cpu.test(Flags::Overflow)), (0xA0, true));
+    }
+    #[test]
+    fn test_cycles() {
+        use super::{Address, System};
+        let mut system = System::new();
+        let program = [
+            0xA2, 0x01, // LDX #$01          2
+            0xBD, 0xFF, 0x12, // LDA $12FF,X 4+1
+            0x9D, 0xFF, 0x12, // STA $12FF,X 5
+            0xF0, 0x80, // BEQ $058A         2+2
+        ];
+        system.bus.load(Address(0x0600), &program);
+        system.bus.write(Address(0xFFFC), 0x00);
+        system.bus.write(Address(0xFFFD), 0x06);
+        assert_eq!(system.needs_reset(), true);
+        system.step().unwrap();
+        assert_eq!(system.needs_reset(), false);
+        assert_eq!(system.cycles, 7 + 2);
+        system.step().unwrap();
+        assert_eq!(system.cycles, 9 + 5);
+        system.step().unwrap();
+        assert_eq!(system.cycles, 14 + 5);
+        system.step().unwrap();
+        assert_eq!(system.cycles, 19 + 4);
+
+        // BRK and a serviced IRQ both take 7
+        system.bus.load(Address(0x058A), &[0x00, 0xEA]); // BRK
+        system.bus.load(Address(0xFFFE), &[0x00, 0x07]);
+        system.bus.load(Address(0x0700), &[0x58]); // CLI
+        system.step().unwrap();
+        assert_eq!(system.registers.PC, Address(0x0700));
+        assert_eq!(system.cycles, 23 + 7);
+        system.step().unwrap();
+        assert_eq!(system.cycles, 30 + 2);
+        system.set_irq(true);
+        system.step().unwrap();
+        assert_eq!(system.registers.PC, Address(0x0700));
+        assert_eq!(system.cycles, 32 + 7);
+    }
+    #[test]
+    fn test_dummy_reads() {
+        use super::{Address, Bus, Ram, System};
+        use std::sync::{Arc, Mutex};
+        // Keeps the dummy reads apart from the real ones
+        struct Logger(Ram, Arc<Mutex<Vec<Address>>>);
+        impl Bus for Logger {
+            fn read(&mut self, addr: Address) -> u8 {
+                self.0.read(addr)
+            }
+            fn write(&mut self, addr: Address, value: u8) {
+                self.0.write(addr, value)
+            }
+            fn dummy_read(&mut self, addr: Address) {
+                self.1.lock().unwrap().push(addr);
+            }
+            fn peek(&self, addr: Address) -> u8 {
+                self.0.peek(addr)
+            }
+        }
+        let dummies = Arc::new(Mutex::new(vec![]));
+        let mut system = System::with_bus(Box::new(Logger(Ram::new(), dummies.clone())));
+        // INX; LDA $12FF,X
+        system.bus.load(Address(0x0600), &[0xE8, 0xBD, 0xFF, 0x12]);
+        system.bus.load(Address(0xFFFC), &[0x00, 0x06]);
+        system.step().unwrap();
+        system.step().unwrap();
+        assert_eq!(*dummies.lock().unwrap(), [Address(0x0601), Address(0x1200)]);
+    }
+    #[test]
+    fn test_errors() {
+        use super::{Address, CpuErrorKind, System};
+        use std::convert::TryFrom;
+        let mut system = System::new();
+        // NOP, then $02 which isn't an instruction
+        system.bus.load(Address(0x0600), &[0xEA, 0x02]);
+        system.bus.load(Address(0xFFFC), &[0x00, 0x06]);
+        system.step().unwrap();
+        let error = system.step().unwrap_err();
+        assert_eq!(error.pc, Address(0x0601));
+        assert_eq!(error.opcode, 0x02);
+        assert!(matches!(error.kind, CpuErrorKind::UnknownOp));
+        // The CPU stays on the instruction
+        assert_eq!(system.registers.PC, Address(0x0601));
+        assert!(system.step().is_err());
+
+        assert!(matches!(
+            Address::try_from(0x10000usize),
+            Err(CpuErrorKind::AddressOverflow(0x10000))
+        ));
+        assert!(matches!(
+            Address::try_from(-1),
+            Err(CpuErrorKind::AddressOverflow(-1))
+        ));
+        assert!(Address(0xFFFF).checked_add(1usize).is_err());
+        assert_eq!(
+            Address(0xFFFE).checked_add(1usize).unwrap(),
+            Address(0xFFFF)
+        );
+    }
+    #[test]
+    fn test_illegal_opcodes() {
+        use super::{Address, CpuErrorKind, Flags, IllegalOpcodes, System};
+        let program = [
+            0xA7, 0x10, // LAX $10
+            0x07, 0x11, // SLO $11
+            0xC7, 0x12, // DCP $12
+            0x87, 0x13, // SAX $13
+            0x1C, 0xFF, 0x12, // NOP $12FF,X
+            0x02, // JAM
+            0x8B, 0x00, // ANE #$00
+        ];
+        let setup = |policy| {
+            let mut system = System::new();
+            system.illegal_opcodes = policy;
+            system.bus.load(Address(0x0600), &program);
+            // NMI and reset both go to the program
+            system.bus.load(Address(0xFFFA), &[0x00, 0x06, 0x00, 0x06]);
+            system.bus.load(Address(0x0010), &[0x81, 0x40, 0x01]);
+            system
+        };
+
+        let mut system = setup(IllegalOpcodes::Halt);
+        let error = system.step().unwrap_err();
+        assert!(matches!(error.kind, CpuErrorKind::UnknownOp));
+
+        let mut system = setup(IllegalOpcodes::Nop);
+        system.watchpoints.push("read $10-$13".parse().unwrap());
+        for _ in 0..6 {
+            system.step().unwrap();
+        }
+        assert_eq!(system.registers.PC, Address(0x060C));
+        assert_eq!(system.registers.A, 0x00);
+        assert_eq!(system.bus.peek(Address(0x0011)), 0x40);
+        assert_eq!(system.cycles, 7 + 3 + 5 + 5 + 3 + 4 + 2);
+        // Skipped opcodes don't read their operands
+        assert!(system.take_watch_hit().is_none());
+        // Indexed ones take as long as the real instruction, without reading their pointers
+        let mut nops = setup(IllegalOpcodes::Nop);
+        // SLO $12FF,X; LAX ($40),Y
+        nops.bus
+            .load(Address(0x0600), &[0x1F, 0xFF, 0x12, 0xB3, 0x40]);
+        nops.bus.load(Address(0x0040), &[0xFF, 0x12]);
+        nops.registers.X = 0x01;
+        nops.registers.Y = 0x01;
+        nops.watchpoints.push("read $40-$41".parse().unwrap());
+        nops.step().unwrap();
+        nops.step().unwrap();
+        assert_eq!(nops.cycles, 7 + 7 + 6);
+        assert!(nops.take_watch_hit().is_none());
+        // and the policy goes along with save states
+        let state = system.snapshot();
+        let mut other = setup(IllegalOpcodes::Emulate);
+        other.restore(&state).unwrap();
+        assert_eq!(other.illegal_opcodes, IllegalOpcodes::Nop);
+
+        let mut system = setup(IllegalOpcodes::Emulate);
+        for _ in 0..5 {
+            system.step().unwrap();
+        }
+        assert_eq!(system.registers.A, 0x81);
+        assert_eq!(system.registers.X, 0x81);
+        assert_eq!(system.bus.peek(Address(0x0011)), 0x80);
+        assert_eq!(system.bus.peek(Address(0x0012)), 0x00);
+        assert_eq!(system.bus.peek(Address(0x0013)), 0x81);
+        assert_eq!(system.registers.test(Flags::Carry), true);
+        assert_eq!(system.registers.test(Flags::Negative), true);
+        // The NOP crossed a page
+        assert_eq!(system.cycles, 7 + 3 + 5 + 5 + 3 + 5);
+        // JAM stops everything, even NMIs, until a reset
+        system.step().unwrap();
+        system.set_nmi(true);
+        system.step().unwrap();
+        assert_eq!(system.jammed(), true);
+        assert_eq!(system.registers.PC, Address(0x060B));
+        // The NMI is still pending after the reset
+        system.reset();
+        system.step().unwrap();
+        assert_eq!(system.jammed(), false);
+        assert_eq!(system.registers.PC, Address(0x0600));
+
+        system.registers.PC = Address(0x060C);
+        let error = system.step().unwrap_err();
+        assert!(matches!(error.kind, CpuErrorKind::UnstableOp));
+    }
 }
@@ -0,0 +1,97 @@
+use super::bus::Bus;
+use super::components::Registers;
+use super::disassembler::decode;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Writes a line for every instruction, before running it:
+///
+/// `0600  A9 10     LDA #$10                        A:00 X:00 Y:00 P:24 SP:FD CYC:7`
+///
+/// The columns line up with nestest's log, without its PPU counters and effective addresses
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+}
+impl Tracer {
+    pub fn new<W: Write + Send + 'static>(out: W) -> Self {
+        Self { out: Box::new(out) }
+    }
+    /// Creates or truncates the file
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+    pub fn log(&mut self, bus: &dyn Bus, registers: &Registers, cycles: usize) -> io::Result<()> {
+        writeln!(self.out, "{}", line(bus, registers, cycles))
+    }
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// The trace line for the instruction at PC
+pub fn line(bus: &dyn Bus, registers: &Registers, cycles: usize) -> String {
+    format!(
+        "{:<47} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+        decode(bus, registers.PC).to_string(),
+        registers.A,
+        registers.X,
+        registers.Y,
+        registers.flags,
+        registers.S,
+        cycles
+    )
+}
+
+mod test {
+    #[test]
+    fn test_trace() {
+        use crate::emulator::addressing_modes::Address;
+        use crate::emulator::system::System;
+        let mut system = System::new();
+        // LDX #$10; STX $0200
+        system
+            .bus
+            .load(Address(0x0600), &[0xA2, 0x10, 0x8E, 0x00, 0x02]);
+        system.bus.load(Address(0xFFFC), &[0x00, 0x06]);
+        let out = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        system.trace = Some(super::Tracer::new(Shared(out.clone())));
+        system.step().unwrap();
+        system.step().unwrap();
+        let out = out.lock().unwrap();
+        let lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "0600  A2 10     LDX #$10                        A:00 X:00 Y:00 P:24 SP:FD CYC:7",
+                "0602  8E 00 02  STX $0200                       A:00 X:10 Y:00 P:24 SP:FD CYC:9",
+            ]
+        );
+
+        // A trace that can't be written stops, and the error is kept for the frontend
+        system.trace = Some(super::Tracer::new(Full));
+        system.step().unwrap();
+        assert!(system.trace.is_none());
+        assert!(system.take_trace_error().is_some());
+        assert!(system.take_trace_error().is_none());
+
+        struct Shared(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl std::io::Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        struct Full;
+        impl std::io::Write for Full {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::WriteZero.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+    }
+}
@@ -21,4 +21,6 @@ macro_rules! prog_err {
     };
 }
 
+prog_err!(crate::emulator::MachineError, std::io::Error, String);
+#[cfg(feature = "gui")]
 prog_err!(glib::BoolError);
@@ -0,0 +1,382 @@
+//! GDB remote serial protocol server.
+//!
+//! Start the emulator with `--gdb <port>` and attach with `target remote localhost:<port>`.
+//! Commands go through `ThreadedEmulator`, so the window keeps updating while a debugger
+//! is attached. Registers are `a`, `x`, `y`, `p`, `sp` (8 bits) and `pc` (16 bits),
+//! described to GDB with a target description since it has no 6502 support of its own
+use crate::emulator::debugger::{Access, Breakpoint, StopReason, Watchpoint};
+use crate::emulator::{Address, System};
+use crate::handler::{Cmd, ThreadedEmulator};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.crustacean.m6502">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="p" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Listens on localhost:`port`, serving one debugger at a time
+pub fn serve(port: u16, emulator: &ThreadedEmulator) -> io::Result<thread::JoinHandle<()>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let tcmd = emulator.tcmd.clone();
+    let system = emulator.system.clone();
+    // Subscribing for each connection would need the emulator in this thread
+    let stops = emulator.subscribe();
+    println!("Waiting for GDB on port {}", port);
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("GDB connection failed: {}", e);
+                    continue;
+                }
+            };
+            let mut session = Session {
+                stream,
+                tcmd: tcmd.clone(),
+                system: system.clone(),
+                stops: &stops,
+                no_ack: false,
+                last_stop: "S05".to_string(),
+            };
+            match session.run() {
+                Ok(()) => println!("GDB detached"),
+                Err(e) => println!("GDB connection closed: {}", e),
+            }
+        }
+    }))
+}
+
+struct Session<'a> {
+    stream: TcpStream,
+    tcmd: mpsc::Sender<Cmd>,
+    system: Arc<Mutex<System>>,
+    stops: &'a mpsc::Receiver<StopReason>,
+    no_ack: bool,
+    /// Reply to `?`
+    last_stop: String,
+}
+impl<'a> Session<'a> {
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => continue, // ^C while stopped
+            };
+            match packet.as_str() {
+                "D" => return self.send("OK"),
+                "k" => return Ok(()),
+                _ => {}
+            }
+            let reply = self.handle(&packet)?;
+            self.send(&reply)?;
+            if packet == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+    }
+
+    /* #region Framing */
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8];
+        self.stream.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+    /// Reads the next packet, acknowledging it. `None` is a ^C
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                b'$' => {}
+                0x03 => return Ok(None),
+                _ => continue, // Acks
+            }
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let digits = [self.read_byte()?, self.read_byte()?];
+            let found = std::str::from_utf8(&digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+            let valid = found == Some(checksum(&data));
+            if !self.no_ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+    /* #endregion */
+
+    fn handle(&mut self, packet: &str) -> io::Result<String> {
+        // Packets may be empty, or start with anything
+        let kind = packet.get(..1).unwrap_or("");
+        let args = packet.get(1..).unwrap_or("");
+        Ok(match kind {
+            "?" => self.last_stop.clone(),
+            "g" => {
+                let system = self.lock();
+                let r = &system.registers;
+                let pc = *r.PC;
+                hex(&[r.A, r.X, r.Y, r.flags, r.S, pc as u8, (pc >> 8) as u8])
+            }
+            "G" => match unhex(args) {
+                Some(bytes) if bytes.len() == 7 => {
+                    let mut system = self.lock();
+                    let r = &mut system.registers;
+                    r.A = bytes[0];
+                    r.X = bytes[1];
+                    r.Y = bytes[2];
+                    r.flags = bytes[3];
+                    r.S = bytes[4];
+                    r.PC = Address((bytes[6] as usize) << 8 | bytes[5] as usize);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(5) => {
+                    let pc = *self.lock().registers.PC;
+                    hex(&[pc as u8, (pc >> 8) as u8])
+                }
+                Ok(n) if n < 5 => hex(&[self.register(n)]),
+                _ => "E01".to_string(),
+            },
+            "P" => self
+                .write_register(args)
+                .unwrap_or_else(|| "E01".to_string()),
+            "m" => match range(args) {
+                Some((addr, len)) => {
+                    let system = self.lock();
+                    // The length comes from the client. Past 64 KiB it would only wrap around
+                    let bytes: Vec<u8> = (0..len.min(0x10000))
+                        .map(|i| system.bus.peek(addr.wrapping_add(i)))
+                        .collect();
+                    hex(&bytes)
+                }
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let target = parts.next().and_then(range);
+                let data = parts.next().and_then(unhex);
+                match (target, data) {
+                    (Some((addr, len)), Some(data)) if data.len() == len => {
+                        self.lock().bus.load(addr, &data);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "c" | "s" => {
+                if let Ok(addr) = usize::from_str_radix(args, 16) {
+                    self.lock().registers.PC = Address(addr & 0xFFFF);
+                }
+                // One instruction takes at least one cycle
+                let cmd = if kind == "c" {
+                    Cmd::Run
+                } else {
+                    Cmd::RunFor(1)
+                };
+                self.resume(cmd)?
+            }
+            "Z" | "z" => self
+                .set_trap(kind == "Z", args)
+                .unwrap_or_else(|| "E01".to_string()),
+            "H" | "T" => "OK".to_string(),
+            _ => self.query(packet),
+        })
+    }
+
+    /// General queries and packets without a fixed one letter prefix
+    fn query(&mut self, packet: &str) -> String {
+        const FEATURES: &str = "qXfer:features:read:target.xml:";
+        if packet.starts_with("qSupported") {
+            "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".to_string()
+        } else if let Some(args) = packet.strip_prefix(FEATURES) {
+            match range(args) {
+                Some((offset, len)) => {
+                    let offset = (*offset).min(TARGET_XML.len());
+                    let end = (offset + len).min(TARGET_XML.len());
+                    let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                    format!("{}{}", more, &TARGET_XML[offset..end])
+                }
+                None => "E01".to_string(),
+            }
+        } else {
+            match packet {
+                "QStartNoAckMode" => "OK",
+                "qAttached" => "1",
+                "qC" => "QC1",
+                "qfThreadInfo" => "m1",
+                "qsThreadInfo" => "l",
+                _ => "", // Unsupported
+            }
+            .to_string()
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, System> {
+        self.system
+            .lock()
+            .unwrap_or_else(|e| panic!("Error acquiring lock for the system. Error: {}", e))
+    }
+    fn register(&self, n: usize) -> u8 {
+        let r = &self.lock().registers;
+        [r.A, r.X, r.Y, r.flags, r.S][n]
+    }
+    /// `n=value`, with the value in target (little endian) byte order
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let mut parts = args.splitn(2, '=');
+        let n = usize::from_str_radix(parts.next()?, 16).ok()?;
+        let bytes = unhex(parts.next()?)?;
+        let mut system = self.lock();
+        let r = &mut system.registers;
+        match (n, bytes.as_slice()) {
+            (0, [value]) => r.A = *value,
+            (1, [value]) => r.X = *value,
+            (2, [value]) => r.Y = *value,
+            (3, [value]) => r.flags = *value,
+            (4, [value]) => r.S = *value,
+            (5, [lo, hi]) => r.PC = Address((*hi as usize) << 8 | *lo as usize),
+            _ => return None,
+        }
+        Some("OK".to_string())
+    }
+    /// `Z`/`z` packets: `type,addr,kind`. Types 0 and 1 are breakpoints, 2 to 4 watchpoints
+    fn set_trap(&mut self, insert: bool, args: &str) -> Option<String> {
+        let mut parts = args.splitn(2, ',');
+        let kind = parts.next()?;
+        let (addr, len) = range(parts.next()?)?;
+        let access = match kind {
+            "0" | "1" => {
+                let cmd = if insert {
+                    Cmd::AddBreakpoint(Breakpoint {
+                        addr,
+                        condition: None,
+                    })
+                } else {
+                    Cmd::RemoveBreakpoint(addr)
+                };
+                self.command(cmd).ok()?;
+                return Some("OK".to_string());
+            }
+            "2" => Access::Write,
+            "3" => Access::Read,
+            "4" => Access::Any,
+            _ => return Some(String::new()),
+        };
+        let watchpoint = Watchpoint {
+            start: addr,
+            end: addr.wrapping_add(len.max(1) - 1),
+            access,
+        };
+        let cmd = if insert {
+            Cmd::AddWatchpoint(watchpoint)
+        } else {
+            Cmd::RemoveWatchpoint(watchpoint)
+        };
+        self.command(cmd).ok()?;
+        Some("OK".to_string())
+    }
+
+    fn command(&self, cmd: Cmd) -> io::Result<()> {
+        self.tcmd
+            .send(cmd)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The emulator stopped"))
+    }
+
+    /// Runs `cmd` and waits for the CPU to stop, passing on ^C from the debugger.
+    /// Returns the stop reply
+    fn resume(&mut self, cmd: Cmd) -> io::Result<String> {
+        // Stops from runs started by the window
+        while self.stops.try_recv().is_ok() {}
+        self.command(cmd)?;
+        self.stream
+            .set_read_timeout(Some(Duration::from_millis(20)))?;
+        let reason = loop {
+            if let Ok(reason) = self.stops.try_recv() {
+                break reason;
+            }
+            let mut byte = [0u8];
+            match self.stream.read(&mut byte) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) if byte[0] == 0x03 => self.command(Cmd::Stop)?,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        };
+        self.stream.set_read_timeout(None)?;
+        self.last_stop = match reason {
+            StopReason::Requested => "S02".to_string(), // SIGINT
+            StopReason::Fault(_) => "S04".to_string(),  // SIGILL
+            StopReason::Watchpoint(hit) => {
+                // The hit has the access the CPU did, the kind is the one of the watchpoint
+                let watched = self
+                    .lock()
+                    .watchpoints
+                    .iter()
+                    .find(|w| w.matches(hit.addr, hit.access))
+                    .map(|w| w.access);
+                let kind = match watched {
+                    Some(Access::Write) => "watch",
+                    Some(Access::Any) => "awatch",
+                    _ => "rwatch",
+                };
+                format!("T05{}:{:04x};", kind, *hit.addr)
+            }
+            _ => "S05".to_string(), // SIGTRAP
+        };
+        Ok(self.last_stop.clone())
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+/// `addr,length`, both in hex
+fn range(text: &str) -> Option<(Address, usize)> {
+    let mut parts = text.splitn(2, ',');
+    let addr = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    if addr > 0xFFFF {
+        return None;
+    }
+    Some((Address(addr), len))
+}
@@ -1,23 +1,59 @@
-use crate::emulator::{CpuError, System};
+use crate::emulator::debugger::{self, Breakpoint, Debugger, StopReason, Watchpoint};
+use crate::emulator::loader::{self, LoadOptions};
+use crate::emulator::rewind::History;
+use crate::emulator::snapshot::Snapshot;
+use crate::emulator::trace::Tracer;
+use crate::emulator::{Address, CpuError, MachineConfig, MachineError, System};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 
-static TEST_CODE: &'static [u8; 0x10000] = include_bytes!("color.hex");
-
 /* #region Commands */
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Cmd {
     Step,
+    /// Runs a JSR until it returns. Anything else is a single step
+    StepOver,
+    /// Runs until an RTS or RTI leaves the current subroutine
+    StepOut,
     Run,
     Stop,
     Get(GetType),
     Reset,
+    Irq(bool),
+    Nmi(bool),
+    /// Loads a program and resets. Later resets load it again from disk
+    Load(PathBuf, LoadOptions),
+    /// Runs until PC gets to the address
+    RunUntil(Address),
+    /// Runs for at least this many cycles
+    RunFor(usize),
+    AddBreakpoint(Breakpoint),
+    RemoveBreakpoint(Address),
+    AddWatchpoint(Watchpoint),
+    RemoveWatchpoint(Watchpoint),
+    /// Removes every breakpoint and watchpoint
+    ClearBreakpoints,
+    /// Starts tracing every instruction to the file, or stops with `None`
+    Trace(Option<PathBuf>),
+    SaveState(PathBuf),
+    LoadState(PathBuf),
+    /// Saves to a numbered slot next to the program
+    QuickSave(u8),
+    QuickLoad(u8),
+    /// Goes back one instruction
+    StepBack,
+    /// Goes back this many cycles
+    Rewind(usize),
 }
 impl std::convert::From<&str> for Cmd {
     fn from(text: &str) -> Self {
         match text {
             "Step" => Self::Step,
+            "StepOver" => Self::StepOver,
+            "StepOut" => Self::StepOut,
+            "StepBack" => Self::StepBack,
             "Run" => Self::Run,
             "Stop" => Self::Stop,
             "Reset" => Self::Reset,
@@ -34,79 +70,423 @@ pub enum GetType {
 }
 /* #endregion */
 
+/// What the emulator thread sends to the UI
+#[derive(Clone, Debug, PartialEq)]
+pub enum Data {
+    /// Page $02, after every step
+    Page(Vec<u8>),
+    /// Answer to `Cmd::Get`
+    Memory(Vec<u8>),
+    /// A run stopped
+    Stopped(StopReason),
+    /// Something to show in the status bar
+    Message(String),
+    /// The CPU couldn't run an instruction. It stays on it until a reset or a state is loaded
+    Error(String),
+}
+
+/// When a run stops on its own
+enum Until {
+    Forever,
+    Address(Address),
+    Cycle(usize),
+    /// PC gets to the address with the stack at least as high as S was
+    Return(Address, u8),
+    /// An RTS or RTI brings S above this
+    Out(u8),
+}
+
+/// How far back Step Back and Rewind can go: a snapshot every 10000 cycles, 64 of them
+const HISTORY_SNAPSHOTS: usize = 64;
+const HISTORY_INTERVAL: usize = 10_000;
+
+/// Get a copy of every stop reason, besides the UI
+type Listeners = Arc<Mutex<Vec<mpsc::Sender<StopReason>>>>;
+
 pub struct ThreadedEmulator {
     pub tcmd: mpsc::Sender<Cmd>,
-    pub rdata: glib::Receiver<Vec<u8>>,
+    pub rdata: glib::Receiver<Data>,
     pub system: Arc<Mutex<System>>,
-    pub thread: thread::JoinHandle<Result<(), CpuError>>,
+    pub thread: thread::JoinHandle<()>,
+    listeners: Listeners,
 }
 impl ThreadedEmulator {
-    pub fn new() -> Self {
+    /// Without a machine, the system is a flat 64 KiB of RAM
+    pub fn new(machine: Option<MachineConfig>) -> Result<Self, MachineError> {
         let (tcmd, rcmd) = mpsc::channel::<Cmd>();
         let (tdata, rdata) = glib::MainContext::channel(glib::source::Priority::default());
-        let system = Arc::from(Mutex::from(System::new()));
+        let system = match machine {
+            Some(ref machine) => machine.build()?,
+            None => System::new(),
+        };
+        let system = Arc::from(Mutex::from(system));
+        let listeners: Listeners = Arc::default();
         let thread = {
             let system = system.clone();
-            thread::spawn(move || Self::thread(rcmd, tdata, system))
+            let listeners = listeners.clone();
+            thread::spawn(move || Self::thread(rcmd, tdata, system, machine, listeners))
         };
-        Self {
+        Ok(Self {
             tcmd,
             rdata,
             system,
             thread,
+            listeners,
+        })
+    }
+
+    /// Receives why every run from now on stopped, whoever started it
+    pub fn subscribe(&self) -> mpsc::Receiver<StopReason> {
+        let (tstop, rstop) = mpsc::channel();
+        self.listeners
+            .lock()
+            .expect("Couldn't lock the listeners")
+            .push(tstop);
+        rstop
+    }
+
+    /// Restarts the system and loads the program, if there's one.
+    /// Tells the UI when either fails
+    fn reset(
+        system: &mut System,
+        machine: &Option<MachineConfig>,
+        program: &Option<(PathBuf, LoadOptions)>,
+        tdata: &glib::Sender<Data>,
+    ) {
+        let send = |message| {
+            tdata
+                .send(Data::Message(message))
+                .expect("Couldn't send the message");
+        };
+        match machine {
+            // Rebuilding picks up any change to the ROM files
+            Some(machine) => match machine.build() {
+                Ok(mut new_system) => {
+                    new_system.watchpoints = std::mem::take(&mut system.watchpoints);
+                    new_system.trace = system.trace.take();
+                    *system = new_system;
+                }
+                Err(e) => send(e.to_string()),
+            },
+            None => system.restart(),
+        }
+        if let Some((path, options)) = program {
+            match loader::load_file(&mut *system.bus, path, *options) {
+                Ok(ranges) => {
+                    for (start, end) in ranges {
+                        println!("Loaded ${:04X}-${:04X}", *start, *end);
+                    }
+                }
+                Err(e) => send(format!("Couldn't load {}: {}", path.display(), e)),
+            }
+        }
+    }
+
+    /// `game.s` saves slot 1 to `game.state1`. Without a program, it goes in the working directory
+    fn slot_path(program: &Option<(PathBuf, LoadOptions)>, slot: u8) -> PathBuf {
+        let extension = format!("state{}", slot);
+        match program {
+            Some((path, _)) => path.with_extension(extension),
+            None => Path::new("quicksave").with_extension(extension),
+        }
+    }
+
+    fn save_state(system: &System, path: &Path, tdata: &glib::Sender<Data>) {
+        let message = match system.snapshot().save(path) {
+            Ok(()) => format!("Saved the state to {}", path.display()),
+            Err(e) => format!("Couldn't save {}: {}", path.display(), e),
+        };
+        tdata
+            .send(Data::Message(message))
+            .expect("Couldn't send the message");
+    }
+
+    fn load_state(system: &mut System, path: &Path, tdata: &glib::Sender<Data>) {
+        let message = match Snapshot::load(path).and_then(|state| system.restore(&state)) {
+            Ok(()) => {
+                let page_02 = system.bus.peek_range(Address(0x200), Address(0x300));
+                tdata
+                    .send(Data::Page(page_02))
+                    .expect("Couldn't send page $02");
+                format!("Loaded the state from {}", path.display())
+            }
+            Err(e) => format!("Couldn't load {}: {}", path.display(), e),
+        };
+        tdata
+            .send(Data::Message(message))
+            .expect("Couldn't send the message");
+    }
+
+    /// Tells the UI where Step Back or Rewind ended up
+    fn rewound(system: &System, went_back: bool, tdata: &glib::Sender<Data>) {
+        let message = match went_back {
+            true => {
+                let page_02 = system.bus.peek_range(Address(0x200), Address(0x300));
+                tdata
+                    .send(Data::Page(page_02))
+                    .expect("Couldn't send page $02");
+                format!("Went back to cycle {}", system.cycles)
+            }
+            false => "Nothing to go back to".to_string(),
+        };
+        tdata
+            .send(Data::Message(message))
+            .expect("Couldn't send the message");
+    }
+
+    /// Handles the commands that don't interrupt a run.
+    /// Gives back the ones it doesn't handle
+    fn configure(
+        system: &mut System,
+        debugger: &mut Debugger,
+        cmd: Cmd,
+        tdata: &glib::Sender<Data>,
+    ) -> Option<Cmd> {
+        match cmd {
+            Cmd::Irq(asserted) => system.set_irq(asserted),
+            Cmd::Nmi(asserted) => system.set_nmi(asserted),
+            Cmd::AddBreakpoint(breakpoint) => debugger.add_breakpoint(breakpoint),
+            Cmd::RemoveBreakpoint(addr) => debugger.remove_breakpoint(addr),
+            Cmd::AddWatchpoint(watchpoint) => system.watchpoints.push(watchpoint),
+            Cmd::RemoveWatchpoint(watchpoint) => system.watchpoints.retain(|w| *w != watchpoint),
+            Cmd::ClearBreakpoints => {
+                debugger.breakpoints.clear();
+                system.watchpoints.clear();
+            }
+            Cmd::Trace(None) => system.trace = None,
+            Cmd::Trace(Some(path)) => match Tracer::to_file(&path) {
+                Ok(trace) => system.trace = Some(trace),
+                Err(e) => {
+                    let message = format!("Couldn't trace to {}: {}", path.display(), e);
+                    tdata
+                        .send(Data::Message(message))
+                        .expect("Couldn't send the message");
+                }
+            },
+            cmd => return Some(cmd),
+        }
+        None
+    }
+
+    fn lock(system: &Mutex<System>) -> MutexGuard<'_, System> {
+        system
+            .lock()
+            .unwrap_or_else(|e| panic!("Error acquiring lock for the system. Error: {}", e))
+    }
+
+    /// Steps until `until` is met, a breakpoint or watchpoint triggers, or a command arrives.
+    /// Returns the command that interrupted the run, for the caller to handle.
+    /// The system is only locked during each step, so the debug servers can look at it meanwhile
+    fn run(
+        system: &Mutex<System>,
+        history: &mut History,
+        debugger: &mut Debugger,
+        rcmd: &mpsc::Receiver<Cmd>,
+        tdata: &glib::Sender<Data>,
+        listeners: &Listeners,
+        until: Until,
+    ) -> Option<Cmd> {
+        let start = {
+            let mut system = Self::lock(system);
+            system.take_watch_hit(); // From before the run
+            system.cycles
+        };
+        let (reason, next) = loop {
+            match rcmd.recv_timeout(std::time::Duration::from_millis(1)) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    let mut system = Self::lock(system);
+                    let system = &mut *system;
+                    let opcode = system.bus.peek(system.registers.PC);
+                    if let Err(e) = Self::step(system, history, tdata) {
+                        Self::fault(&e, tdata);
+                        break (StopReason::Fault(e.pc), None);
+                    }
+                    if let Some(reason) = debugger.check(system) {
+                        break (reason, None);
+                    }
+                    let (pc, s) = (system.registers.PC, system.registers.S);
+                    match until {
+                        Until::Address(addr) if pc == addr => {
+                            break (StopReason::Reached(addr), None)
+                        }
+                        Until::Cycle(cycle) if system.cycles >= cycle => {
+                            break (StopReason::CyclesElapsed(system.cycles - start), None)
+                        }
+                        // Recursive calls come back to the same address with a deeper stack
+                        Until::Return(addr, depth) if pc == addr && s >= depth => {
+                            break (StopReason::Reached(addr), None)
+                        }
+                        Until::Out(depth) if debugger::is_return(opcode) && s > depth => {
+                            break (StopReason::Returned(pc), None)
+                        }
+                        _ => {}
+                    }
+                }
+                Err(e) => panic!("Controller mpsc disconnected: {}", e),
+                // Interrupt lines and breakpoints can change without stopping the CPU
+                Ok(cmd) => match Self::configure(&mut Self::lock(system), debugger, cmd, tdata) {
+                    None => {}
+                    Some(Cmd::Stop) => break (StopReason::Requested, None),
+                    Some(cmd) => break (StopReason::Requested, Some(cmd)),
+                },
+            }
+        };
+        Self::flush_trace(&mut Self::lock(system), tdata);
+        tdata
+            .send(Data::Stopped(reason))
+            .expect("Couldn't send the stop reason");
+        // Listeners that went away are dropped
+        listeners
+            .lock()
+            .expect("Couldn't lock the listeners")
+            .retain(|listener| listener.send(reason).is_ok());
+        next
+    }
+
+    /// Writes out the trace, telling the UI if that or any earlier write failed
+    fn flush_trace(system: &mut System, tdata: &glib::Sender<Data>) {
+        system.flush_trace();
+        if let Some(e) = system.take_trace_error() {
+            tdata
+                .send(Data::Message(format!("Tracing stopped: {}", e)))
+                .expect("Couldn't send the message");
         }
     }
 
+    fn fault(error: &CpuError, tdata: &glib::Sender<Data>) {
+        println!("{}", error);
+        tdata
+            .send(Data::Error(error.to_string()))
+            .expect("Couldn't send the error");
+    }
+
+    /// Steps once and sends page $02
+    fn step(
+        system: &mut System,
+        history: &mut History,
+        tdata: &glib::Sender<Data>,
+    ) -> Result<(), CpuError> {
+        history.record(system);
+        system.step()?;
+        let page_02 = system.bus.peek_range(Address(0x200), Address(0x300));
+        tdata.send(Data::Page(page_02));
+        Ok(())
+    }
+
     fn thread(
         rcmd: mpsc::Receiver<Cmd>,
-        tdata: glib::Sender<Vec<u8>>,
-        system: Arc<Mutex<System>>,
-    ) -> Result<(), CpuError> {
+        tdata: glib::Sender<Data>,
+        shared: Arc<Mutex<System>>,
+        machine: Option<MachineConfig>,
+        listeners: Listeners,
+    ) {
+        let mut program: Option<(PathBuf, LoadOptions)> = None;
+        let mut debugger = Debugger::default();
+        let mut history = History::new(HISTORY_SNAPSHOTS, HISTORY_INTERVAL);
+        // A command that interrupted a run
+        let mut next: Option<Cmd> = None;
         loop {
-            if let Ok(cmd) = rcmd.recv() {
-                let mut system = system.lock().unwrap_or_else(|e| {
-                    panic!("Error acquiring lock for the system. Error: {}", e)
-                });
+            if let Some(cmd) = next.take().or_else(|| rcmd.recv().ok()) {
                 println!("Cmd: {:?}", cmd);
-                match cmd {
-                    Cmd::Step => {
-                        system.step()?;
-                        let page_02 = Vec::from(&(*system.ram)[0x200..0x300]);
-                        tdata.send(page_02);
+                let mut system = Self::lock(&shared);
+                let cmd = match Self::configure(&mut system, &mut debugger, cmd, &tdata) {
+                    Some(cmd) => cmd,
+                    None => continue,
+                };
+                let until = match cmd {
+                    Cmd::Run => Until::Forever,
+                    Cmd::RunUntil(addr) => Until::Address(addr),
+                    Cmd::RunFor(cycles) => Until::Cycle(system.cycles + cycles),
+                    Cmd::StepOut => Until::Out(system.registers.S),
+                    Cmd::Step | Cmd::StepOver => {
+                        let jsr = match cmd {
+                            Cmd::StepOver => debugger::return_address(&system),
+                            _ => None,
+                        };
+                        if let Some(addr) = jsr {
+                            Until::Return(addr, system.registers.S)
+                        } else {
+                            if let Err(e) = Self::step(&mut system, &mut history, &tdata) {
+                                Self::fault(&e, &tdata);
+                            }
+                            system.take_watch_hit();
+                            Self::flush_trace(&mut system, &tdata);
+                            continue;
+                        }
                     }
                     Cmd::Reset => {
-                        system.restart();
-                        system.ram.load(*TEST_CODE);
+                        history.clear();
+                        Self::reset(&mut system, &machine, &program, &tdata);
+                        continue;
                     }
-                    Cmd::Run => loop {
-                        if let Err(e) = rcmd.recv_timeout(std::time::Duration::from_millis(1)) {
-                            if e == std::sync::mpsc::RecvTimeoutError::Timeout {
-                                system.step()?;
-                                let page_02 = Vec::from(&(*system.ram)[0x200..0x300]);
-                                tdata.send(page_02);
-                            } else {
-                                panic!("Controller mpsc disconnected: {}", e)
-                            }
-                        } else {
-                            break;
+                    Cmd::Load(path, options) => {
+                        program = Some((path, options));
+                        history.clear();
+                        Self::reset(&mut system, &machine, &program, &tdata);
+                        continue;
+                    }
+                    Cmd::SaveState(path) => {
+                        Self::save_state(&system, &path, &tdata);
+                        continue;
+                    }
+                    Cmd::LoadState(path) => {
+                        history.clear();
+                        Self::load_state(&mut system, &path, &tdata);
+                        continue;
+                    }
+                    Cmd::QuickSave(slot) => {
+                        Self::save_state(&system, &Self::slot_path(&program, slot), &tdata);
+                        continue;
+                    }
+                    Cmd::QuickLoad(slot) => {
+                        history.clear();
+                        Self::load_state(&mut system, &Self::slot_path(&program, slot), &tdata);
+                        continue;
+                    }
+                    Cmd::StepBack => {
+                        match history.step_back(&mut system) {
+                            Ok(went_back) => Self::rewound(&system, went_back, &tdata),
+                            Err(e) => Self::fault(&e, &tdata),
                         }
-                    },
-                    Cmd::Get(what) => match what {
-                        GetType::Flags => {}
-                        GetType::Range(start, end) => {
-                            let data = &(*system.ram)[start..end];
-                            tdata
-                                .send(Vec::from(data))
-                                .expect("Couldn't send requested value");
+                        continue;
+                    }
+                    Cmd::Rewind(cycles) => {
+                        let cycle = system.cycles.saturating_sub(cycles);
+                        match history.rewind_to(&mut system, cycle) {
+                            Ok(went_back) => Self::rewound(&system, went_back, &tdata),
+                            Err(e) => Self::fault(&e, &tdata),
                         }
-                        GetType::Value(addr) => {
-                            tdata
-                                .send(vec![(*system.ram)[addr]])
-                                .expect("Couldn't send requested value");
+                        continue;
+                    }
+                    Cmd::Get(what) => {
+                        match what {
+                            GetType::Flags => {}
+                            GetType::Range(start, end) => {
+                                let data = system.bus.peek_range(Address(start), Address(end));
+                                tdata
+                                    .send(Data::Memory(data))
+                                    .expect("Couldn't send requested value");
+                            }
+                            GetType::Value(addr) => {
+                                tdata
+                                    .send(Data::Memory(vec![system.bus.peek(Address(addr))]))
+                                    .expect("Couldn't send requested value");
+                            }
                         }
-                    },
-                    _ => {}
+                        continue;
+                    }
+                    _ => continue,
                 };
+                drop(system);
+                next = Self::run(
+                    &shared,
+                    &mut history,
+                    &mut debugger,
+                    &rcmd,
+                    &tdata,
+                    &listeners,
+                    until,
+                );
             }
         }
     }
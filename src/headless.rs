@@ -0,0 +1,217 @@
+//! Runs a program without a window, for scripts and CI.
+//!
+//! `--headless` runs until a BRK, `--until <addr>`, `--cycles <n>` or `--timeout <seconds>`,
+//! then prints why it stopped, the registers and every `--dump <start>-<end>` range.
+//! `--expect "A == $00"` checks a register at the end. `--no-brk` runs BRK like any other
+//! instruction instead of stopping on it.
+//!
+//! The exit code says how the run ended:
+//! 0 on a BRK or the `--until` address, 1 on errors, 2 when the cycles run out,
+//! 3 on a timeout and 4 when an expectation fails
+use crate::emulator::debugger::Condition;
+use crate::emulator::loader::{self, LoadOptions};
+use crate::emulator::trace::Tracer;
+use crate::emulator::{Address, MachineConfig, System};
+use crate::{take_arg, take_flag};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_CYCLES: i32 = 2;
+pub const EXIT_TIMEOUT: i32 = 3;
+pub const EXIT_EXPECTATION: i32 = 4;
+
+pub struct Options {
+    until: Option<Address>,
+    cycles: Option<usize>,
+    timeout: Option<Duration>,
+    stop_on_brk: bool,
+    dumps: Vec<(Address, Address)>,
+    /// As typed, to report them
+    expectations: Vec<(String, Condition)>,
+}
+impl Options {
+    /// Removes the headless options from `args`. `None` without `--headless`
+    pub fn take(args: &mut Vec<String>) -> Result<Option<Self>, String> {
+        if !take_flag(args, "--headless") {
+            return Ok(None);
+        }
+        let address = |text: String| {
+            text.parse::<Address>()
+                .map_err(|e| format!("Invalid address {}: {}", text, e))
+        };
+        let until = match take_arg(args, "--until") {
+            Some(addr) => Some(address(addr)?),
+            None => None,
+        };
+        let cycles = match take_arg(args, "--cycles") {
+            Some(cycles) => Some(
+                cycles
+                    .parse()
+                    .map_err(|e| format!("Invalid cycle count {}: {}", cycles, e))?,
+            ),
+            None => None,
+        };
+        let timeout = match take_arg(args, "--timeout") {
+            Some(seconds) => {
+                Some(Duration::from_secs_f64(seconds.parse().map_err(|e| {
+                    format!("Invalid timeout {}: {}", seconds, e)
+                })?))
+            }
+            None => None,
+        };
+        let mut dumps = vec![];
+        while let Some(range) = take_arg(args, "--dump") {
+            let (start, end) = match range.find('-') {
+                Some(i) => (range[..i].to_string(), range[i + 1..].to_string()),
+                None => (range.clone(), range.clone()),
+            };
+            let (start, end) = (address(start)?, address(end)?);
+            if end < start {
+                return Err(format!("Invalid range {}", range));
+            }
+            dumps.push((start, end));
+        }
+        let mut expectations = vec![];
+        while let Some(condition) = take_arg(args, "--expect") {
+            let parsed = condition.parse()?;
+            expectations.push((condition, parsed));
+        }
+        Ok(Some(Self {
+            until,
+            cycles,
+            timeout,
+            stop_on_brk: !take_flag(args, "--no-brk"),
+            dumps,
+            expectations,
+        }))
+    }
+}
+
+/// Why the run ended
+enum Outcome {
+    Brk(Address),
+    Reached(Address),
+    CyclesElapsed(usize),
+    TimedOut,
+}
+
+/// Runs the program and returns the exit code
+pub fn run(
+    options: Options,
+    machine: Option<MachineConfig>,
+    program: Option<(PathBuf, LoadOptions)>,
+    trace: Option<PathBuf>,
+) -> i32 {
+    let mut system = match machine {
+        Some(machine) => match machine.build() {
+            Ok(system) => system,
+            Err(e) => {
+                eprintln!("{}", e);
+                return EXIT_ERROR;
+            }
+        },
+        None => System::new(),
+    };
+    if let Some((path, load_options)) = program {
+        if let Err(e) = loader::load_file(&mut *system.bus, &path, load_options) {
+            eprintln!("Couldn't load {}: {}", path.display(), e);
+            return EXIT_ERROR;
+        }
+    }
+    if let Some(path) = trace {
+        match Tracer::to_file(&path) {
+            Ok(trace) => system.trace = Some(trace),
+            Err(e) => {
+                eprintln!("Couldn't trace to {}: {}", path.display(), e);
+                return EXIT_ERROR;
+            }
+        }
+    }
+    let outcome = match execute(&mut system, &options) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            eprintln!("{}", e);
+            report(&mut system, &options);
+            return EXIT_ERROR;
+        }
+    };
+    let mut code = match outcome {
+        Outcome::Brk(addr) => {
+            println!("BRK at ${:04X}", *addr);
+            EXIT_OK
+        }
+        Outcome::Reached(addr) => {
+            println!("Reached ${:04X}", *addr);
+            EXIT_OK
+        }
+        Outcome::CyclesElapsed(cycles) => {
+            println!("Ran for {} cycles", cycles);
+            EXIT_CYCLES
+        }
+        Outcome::TimedOut => {
+            println!("Timed out");
+            EXIT_TIMEOUT
+        }
+    };
+    report(&mut system, &options);
+    for (text, expectation) in &options.expectations {
+        if !expectation.holds(&system.registers) {
+            eprintln!("Expectation failed: {}", text);
+            code = EXIT_EXPECTATION;
+        }
+    }
+    code
+}
+
+fn execute(system: &mut System, options: &Options) -> Result<Outcome, String> {
+    let start = Instant::now();
+    // Puts PC on the reset vector before checking it
+    system.init().map_err(|e| e.to_string())?;
+    let mut steps = 0usize;
+    loop {
+        let pc = system.registers.PC;
+        if options.until == Some(pc) {
+            return Ok(Outcome::Reached(pc));
+        }
+        if options.stop_on_brk && system.bus.peek(pc) == 0x00 {
+            return Ok(Outcome::Brk(pc));
+        }
+        if let Some(cycles) = options.cycles {
+            if system.cycles >= cycles {
+                return Ok(Outcome::CyclesElapsed(system.cycles));
+            }
+        }
+        // Checking the clock every instruction would slow the run down
+        if let Some(timeout) = options.timeout {
+            if steps.is_multiple_of(1000) && start.elapsed() >= timeout {
+                return Ok(Outcome::TimedOut);
+            }
+        }
+        system.step().map_err(|e| e.to_string())?;
+        steps += 1;
+    }
+}
+
+/// Prints the registers and the requested memory
+fn report(system: &mut System, options: &Options) {
+    system.flush_trace();
+    if let Some(e) = system.take_trace_error() {
+        eprintln!("Tracing stopped: {}", e);
+    }
+    let r = &system.registers;
+    println!(
+        "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X} CYC:{}",
+        r.A, r.X, r.Y, r.flags, r.S, *r.PC, system.cycles
+    );
+    for (start, end) in &options.dumps {
+        let data: Vec<u8> = (**start..=**end)
+            .map(|addr| system.bus.peek(Address(addr)))
+            .collect();
+        for (i, row) in data.chunks(16).enumerate() {
+            let bytes: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+            println!("{:04X}: {}", **start + i * 16, bytes.join(" "));
+        }
+    }
+}
@@ -0,0 +1,16 @@
+//! A 6502 emulator you can embed: the CPU, buses and memory, program loaders, an assembler,
+//! a disassembler and a debugger. None of it needs GTK; the window lives in the binary,
+//! behind the `gui` feature.
+//!
+//! ```
+//! use crustacean_6502_emulator::emulator::{Address, System};
+//!
+//! let mut system = System::new();
+//! // LDA #$2A; STA $0200
+//! system.bus.load(Address(0x0600), &[0xA9, 0x2A, 0x8D, 0x00, 0x02]);
+//! system.bus.load(Address(0xFFFC), &[0x00, 0x06]);
+//! system.step().unwrap();
+//! system.step().unwrap();
+//! assert_eq!(system.bus.peek(Address(0x0200)), 0x2A);
+//! ```
+pub mod emulator;
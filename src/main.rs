@@ -1,17 +1,35 @@
 mod error;
 use error::ProgErr;
 
-use cairo;
+#[cfg(feature = "gui")]
 use gio::prelude::*;
+#[cfg(feature = "gui")]
 use gtk::prelude::*;
+#[cfg(feature = "gui")]
 use gtk::Builder;
+use std::path::PathBuf;
 
-mod emulator;
+use crustacean_6502_emulator::emulator;
+#[cfg(feature = "gui")]
+use emulator::disassembler;
+use emulator::loader::{Format, LoadOptions};
+use emulator::{Address, MachineConfig};
+mod headless;
+// The window and everything that talks to it through `ThreadedEmulator`
+#[cfg(feature = "gui")]
+mod dap;
+#[cfg(feature = "gui")]
+mod gdb;
+#[cfg(feature = "gui")]
 mod graphic;
+#[cfg(feature = "gui")]
 mod handler;
+#[cfg(feature = "gui")]
 use graphic::{Color, Image};
-use handler::{Cmd, ThreadedEmulator};
+#[cfg(feature = "gui")]
+use handler::{Cmd, Data, ThreadedEmulator};
 
+#[cfg(feature = "gui")]
 macro_rules! gtk_rs {
     ($builder:expr=>$name:expr) => {
         $builder
@@ -20,12 +38,38 @@ macro_rules! gtk_rs {
     };
 }
 
-pub fn init(app: &gtk::Application) -> Result<(), ProgErr> {
+#[cfg(feature = "gui")]
+pub fn init(
+    app: &gtk::Application,
+    machine: Option<MachineConfig>,
+    program: Option<(PathBuf, LoadOptions)>,
+    gdb_port: Option<u16>,
+    dap_port: Option<u16>,
+    trace: Option<PathBuf>,
+) -> Result<(), ProgErr> {
     use std::sync::{Arc, Mutex};
     let img_m = Arc::from(Mutex::from(Image::new()));
     let palette: Arc<Mutex<_>> = Arc::from(Mutex::from([Color::default(); 16]));
 
-    let emulator = ThreadedEmulator::new();
+    let emulator = ThreadedEmulator::new(machine)?;
+    if let Some(path) = trace {
+        emulator
+            .tcmd
+            .send(Cmd::Trace(Some(path)))
+            .expect("Couldn't send cmd");
+    }
+    if let Some((path, options)) = program {
+        emulator
+            .tcmd
+            .send(Cmd::Load(path, options))
+            .expect("Couldn't send cmd");
+    }
+    if let Some(port) = gdb_port {
+        gdb::serve(port, &emulator)?;
+    }
+    if let Some(port) = dap_port {
+        dap::serve(port, &emulator)?;
+    }
 
     /*
        // Handle emulator CMDs
@@ -95,7 +139,9 @@ pub fn init(app: &gtk::Application) -> Result<(), ProgErr> {
         );
     }
 
-    for widget_name in &["Step", "Reset", "Run", "Stop"] {
+    for widget_name in &[
+        "Step", "StepBack", "StepOver", "StepOut", "Reset", "Run", "Stop",
+    ] {
         let tcmd = emulator.tcmd.clone();
         let widget: gtk::Button = gtk_rs!(builder=>widget_name); // builder.get_object(widget_name).expect("Not found");
         widget.connect_clicked(move |s: &gtk::Button| {
@@ -106,6 +152,139 @@ pub fn init(app: &gtk::Application) -> Result<(), ProgErr> {
         });
     }
 
+    // File > Open
+    {
+        let tcmd = emulator.tcmd.clone();
+        let window = window.clone();
+        let open: gtk::MenuItem = gtk_rs!(builder=>"Open");
+        open.connect_activate(move |_| {
+            let dialog = gtk::FileChooserDialog::with_buttons(
+                Some("Open program"),
+                Some(&window),
+                gtk::FileChooserAction::Open,
+                &[
+                    ("_Cancel", gtk::ResponseType::Cancel),
+                    ("_Open", gtk::ResponseType::Accept),
+                ],
+            );
+            if dialog.run() == gtk::ResponseType::Accept {
+                if let Some(path) = dialog.get_filename() {
+                    tcmd.send(Cmd::Load(path, LoadOptions::default()))
+                        .expect("Couldn't send cmd");
+                }
+            }
+            dialog.close();
+        });
+    }
+
+    // State > Save State and Load State ask for the file, quick slots go next to the program
+    {
+        let tcmd = emulator.tcmd.clone();
+        let window = window.clone();
+        let save: gtk::MenuItem = gtk_rs!(builder=>"SaveState");
+        save.connect_activate(move |_| {
+            let dialog = gtk::FileChooserDialog::with_buttons(
+                Some("Save state"),
+                Some(&window),
+                gtk::FileChooserAction::Save,
+                &[
+                    ("_Cancel", gtk::ResponseType::Cancel),
+                    ("_Save", gtk::ResponseType::Accept),
+                ],
+            );
+            dialog.set_current_name("save.state");
+            dialog.set_do_overwrite_confirmation(true);
+            if dialog.run() == gtk::ResponseType::Accept {
+                if let Some(path) = dialog.get_filename() {
+                    tcmd.send(Cmd::SaveState(path)).expect("Couldn't send cmd");
+                }
+            }
+            dialog.close();
+        });
+    }
+    {
+        let tcmd = emulator.tcmd.clone();
+        let window = window.clone();
+        let load: gtk::MenuItem = gtk_rs!(builder=>"LoadState");
+        load.connect_activate(move |_| {
+            let dialog = gtk::FileChooserDialog::with_buttons(
+                Some("Load state"),
+                Some(&window),
+                gtk::FileChooserAction::Open,
+                &[
+                    ("_Cancel", gtk::ResponseType::Cancel),
+                    ("_Open", gtk::ResponseType::Accept),
+                ],
+            );
+            if dialog.run() == gtk::ResponseType::Accept {
+                if let Some(path) = dialog.get_filename() {
+                    tcmd.send(Cmd::LoadState(path)).expect("Couldn't send cmd");
+                }
+            }
+            dialog.close();
+        });
+    }
+    for slot in 1..=4u8 {
+        for (prefix, cmd) in &[
+            ("QuickSave", Cmd::QuickSave(slot)),
+            ("QuickLoad", Cmd::QuickLoad(slot)),
+        ] {
+            let tcmd = emulator.tcmd.clone();
+            let cmd = cmd.clone();
+            let id = format!("{}{}", prefix, slot);
+            let item: gtk::MenuItem = gtk_rs!(builder=>&id);
+            item.connect_activate(move |_| tcmd.send(cmd.clone()).expect("Couldn't send cmd"));
+        }
+    }
+
+    // Interrupt lines
+    for widget_name in &["IRQ", "NMI"] {
+        let tcmd = emulator.tcmd.clone();
+        let widget: gtk::ToggleButton = gtk_rs!(builder=>widget_name);
+        widget.connect_toggled(move |s: &gtk::ToggleButton| {
+            let asserted = s.get_active();
+            let cmd = match s.get_widget_name().unwrap().as_str() {
+                "IRQ" => Cmd::Irq(asserted),
+                _ => Cmd::Nmi(asserted),
+            };
+            tcmd.send(cmd).expect("Couldn't send cmd");
+        });
+    }
+
+    // Trace asks where to write the log
+    {
+        let tcmd = emulator.tcmd.clone();
+        let window = window.clone();
+        let trace: gtk::ToggleButton = gtk_rs!(builder=>"Trace");
+        trace.connect_toggled(move |s: &gtk::ToggleButton| {
+            if !s.get_active() {
+                tcmd.send(Cmd::Trace(None)).expect("Couldn't send cmd");
+                return;
+            }
+            let dialog = gtk::FileChooserDialog::with_buttons(
+                Some("Trace to"),
+                Some(&window),
+                gtk::FileChooserAction::Save,
+                &[
+                    ("_Cancel", gtk::ResponseType::Cancel),
+                    ("_Save", gtk::ResponseType::Accept),
+                ],
+            );
+            dialog.set_current_name("trace.log");
+            let path = match dialog.run() {
+                gtk::ResponseType::Accept => dialog.get_filename(),
+                _ => None,
+            };
+            dialog.close();
+            match path {
+                Some(path) => tcmd
+                    .send(Cmd::Trace(Some(path)))
+                    .expect("Couldn't send cmd"),
+                None => s.set_active(false),
+            }
+        });
+    }
+
     // Ram Display
     /* {
         let ram_display_window: gtk::Window = gtk_rs!(builder=>"RamDisplayWindow"); // builder.get_object().unwrap();
@@ -124,6 +303,47 @@ pub fn init(app: &gtk::Application) -> Result<(), ProgErr> {
     } */
 
     let registers: gtk::Label = gtk_rs!(builder=>"Registers");
+    let disassembly: gtk::Label = gtk_rs!(builder=>"Disassembly");
+    let status: gtk::Label = gtk_rs!(builder=>"Status");
+    let debug_entry: gtk::Entry = gtk_rs!(builder=>"Debug");
+
+    // Run To takes the address typed in the debug entry
+    {
+        let tcmd = emulator.tcmd.clone();
+        let status = status.clone();
+        let debug_entry = debug_entry.clone();
+        let run_to: gtk::Button = gtk_rs!(builder=>"RunTo");
+        run_to.connect_clicked(move |_| {
+            let text = debug_entry
+                .get_text()
+                .map(|text| text.to_string())
+                .unwrap_or_default();
+            match text.trim().parse::<Address>() {
+                Ok(addr) => tcmd.send(Cmd::RunUntil(addr)).expect("Couldn't send cmd"),
+                Err(_) => status.set_text("Type the address to run to in the debug entry"),
+            }
+        });
+    }
+
+    // Debugger commands
+    {
+        let tcmd = emulator.tcmd.clone();
+        let status = status.clone();
+        debug_entry.connect_activate(move |entry: &gtk::Entry| {
+            let text = entry
+                .get_text()
+                .map(|text| text.to_string())
+                .unwrap_or_default();
+            match parse_debug_command(&text) {
+                Ok(cmd) => {
+                    tcmd.send(cmd).expect("Couldn't send cmd");
+                    status.set_text(&text);
+                    entry.set_text("");
+                }
+                Err(e) => status.set_text(&e),
+            }
+        });
+    }
 
     // Receive GPU page
     {
@@ -131,7 +351,34 @@ pub fn init(app: &gtk::Application) -> Result<(), ProgErr> {
         let palette = palette.clone();
         let img_m = img_m.clone();
         let system = emulator.system.clone();
-        emulator.rdata.attach(None, move |data: Vec<_>| {
+        let window = window.clone();
+        emulator.rdata.attach(None, move |data: Data| {
+            let data = match data {
+                Data::Page(page) => page,
+                Data::Stopped(reason) => {
+                    status.set_text(&reason.to_string());
+                    return glib::Continue(true);
+                }
+                Data::Memory(_) => return glib::Continue(true),
+                Data::Message(message) => {
+                    status.set_text(&message);
+                    return glib::Continue(true);
+                }
+                // The emulator keeps going, but the program needs a reset to get past it
+                Data::Error(message) => {
+                    status.set_text(&message);
+                    let dialog = gtk::MessageDialog::new(
+                        Some(&window),
+                        gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+                        gtk::MessageType::Error,
+                        gtk::ButtonsType::Close,
+                        &message,
+                    );
+                    dialog.run();
+                    dialog.close();
+                    return glib::Continue(true);
+                }
+            };
             println!("Received page");
             for line in data.chunks(16) {
                 println!(
@@ -155,6 +402,16 @@ pub fn init(app: &gtk::Application) -> Result<(), ProgErr> {
 
             if let Ok(system) = system.try_lock() {
                 registers.set_text(&format!("{:#?}", system.registers));
+                // The current instruction is marked with >
+                let pc = system.registers.PC;
+                let code: Vec<String> = disassembler::around(&*system.bus, pc, 4, 8)
+                    .iter()
+                    .map(|line| {
+                        let marker = if line.address == pc { ">" } else { " " };
+                        format!("{} {}", marker, line)
+                    })
+                    .collect();
+                disassembly.set_text(&code.join("\n"));
             }
             drawing_area.queue_draw();
             glib::Continue(true)
@@ -186,10 +443,161 @@ pub fn init(app: &gtk::Application) -> Result<(), ProgErr> {
     Ok(())
 }
 
+/// Reads a command typed in the debug entry: `break $0600 [if A == $10]`, `delete $0600`,
+/// `read|write|access $0200[-$02FF]`, `until $0600`, `cycles 1000`, `rewind 1000`, `clear`
+/// and `trace <file>|off`
+#[cfg(feature = "gui")]
+fn parse_debug_command(text: &str) -> Result<Cmd, String> {
+    let text = text.trim();
+    let (word, rest) = match text.find(' ') {
+        Some(i) => (&text[..i], text[i + 1..].trim()),
+        None => (text, ""),
+    };
+    let address = |text: &str| {
+        text.parse::<Address>()
+            .map_err(|e| format!("Invalid address {}: {}", text, e))
+    };
+    match word {
+        "break" => Ok(Cmd::AddBreakpoint(rest.parse()?)),
+        "delete" => Ok(Cmd::RemoveBreakpoint(address(rest)?)),
+        "read" | "write" | "access" => Ok(Cmd::AddWatchpoint(text.parse()?)),
+        "until" => Ok(Cmd::RunUntil(address(rest)?)),
+        "cycles" => {
+            Ok(Cmd::RunFor(rest.parse().map_err(|e| {
+                format!("Invalid cycle count {}: {}", rest, e)
+            })?))
+        }
+        "rewind" => {
+            Ok(Cmd::Rewind(rest.parse().map_err(|e| {
+                format!("Invalid cycle count {}: {}", rest, e)
+            })?))
+        }
+        "clear" => Ok(Cmd::ClearBreakpoints),
+        "trace" => match rest {
+            "" => Err("trace needs a file, or off".to_string()),
+            "off" => Ok(Cmd::Trace(None)),
+            path => Ok(Cmd::Trace(Some(PathBuf::from(path)))),
+        },
+        _ => Err(format!("Unknown command \"{}\"", word)),
+    }
+}
+
+/// Removes `--name value` from `args`, returning the value
+fn take_arg(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let i = args.iter().position(|arg| arg == name)?;
+    args.remove(i);
+    if i < args.len() {
+        Some(args.remove(i))
+    } else {
+        None
+    }
+}
+
+/// Removes `--name <port>` from `args`, returning the port
+fn take_port(args: &mut Vec<String>, name: &str) -> Result<Option<u16>, String> {
+    match take_arg(args, name) {
+        Some(port) => port
+            .parse::<u16>()
+            .map(Some)
+            .map_err(|e| format!("Invalid port for {} {}: {}", name, port, e)),
+        None => Ok(None),
+    }
+}
+
+/// Removes the flag `name` from `args`, returning whether it was there
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|arg| arg == name) {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Reads how to load the program: `--format ihex|srec|prg|raw|asm`,
+/// `--load-address <addr>` for raw images and `--entry` to point the reset vector to it
+fn take_load_options(args: &mut Vec<String>) -> Result<LoadOptions, String> {
+    let load_address = match take_arg(args, "--load-address") {
+        Some(addr) => Some(
+            addr.parse::<Address>()
+                .map_err(|e| format!("Invalid load address {}: {}", addr, e))?,
+        ),
+        None => None,
+    };
+    let format = match take_arg(args, "--format").as_deref() {
+        Some("ihex") => Some(Format::IntelHex),
+        Some("srec") => Some(Format::SRecord),
+        Some("prg") => Some(Format::Prg),
+        Some("asm") => Some(Format::Assembly),
+        Some("raw") => Some(Format::Raw(load_address.unwrap_or(Address(0x0000)))),
+        Some(other) => return Err(format!("Unknown format {}", other)),
+        None => load_address.map(Format::Raw),
+    };
+    Ok(LoadOptions {
+        format,
+        set_reset_vector: take_flag(args, "--entry"),
+    })
+}
+
 fn main() -> Result<(), ProgErr> {
+    // GTK rejects options it doesn't know, so ours are removed before handing them over
+    let mut args = std::env::args().collect::<Vec<_>>();
+    let machine = match take_arg(&mut args, "--machine") {
+        Some(path) => Some(MachineConfig::from_file(path)?),
+        None => None,
+    };
+    let gdb_port = take_port(&mut args, "--gdb")?;
+    let dap_port = take_port(&mut args, "--dap")?;
+    let trace = take_arg(&mut args, "--trace").map(PathBuf::from);
+    let headless = headless::Options::take(&mut args)?;
+    let options = take_load_options(&mut args)?;
+    // The first argument that isn't an option is the program to load
+    let program = args
+        .iter()
+        .skip(1)
+        .position(|arg| !arg.starts_with('-'))
+        .map(|i| (PathBuf::from(args.remove(i + 1)), options));
+    if let Some(headless) = headless {
+        std::process::exit(headless::run(headless, machine, program, trace));
+    }
+    window(args, machine, program, gdb_port, dap_port, trace)
+}
+
+#[cfg(feature = "gui")]
+fn window(
+    args: Vec<String>,
+    machine: Option<MachineConfig>,
+    program: Option<(PathBuf, LoadOptions)>,
+    gdb_port: Option<u16>,
+    dap_port: Option<u16>,
+    trace: Option<PathBuf>,
+) -> Result<(), ProgErr> {
     let app: gtk::Application =
         gtk::Application::new(Some("com.ducklings_corp.emulator"), Default::default())?;
-    app.connect_activate(|app| init(app).expect("Init failed"));
-    app.run(&std::env::args().collect::<Vec<_>>());
+    app.connect_activate(move |app| {
+        init(
+            app,
+            machine.clone(),
+            program.clone(),
+            gdb_port,
+            dap_port,
+            trace.clone(),
+        )
+        .expect("Init failed")
+    });
+    app.run(&args);
     Ok(())
 }
+
+#[cfg(not(feature = "gui"))]
+fn window(
+    _args: Vec<String>,
+    _machine: Option<MachineConfig>,
+    _program: Option<(PathBuf, LoadOptions)>,
+    _gdb_port: Option<u16>,
+    _dap_port: Option<u16>,
+    _trace: Option<PathBuf>,
+) -> Result<(), ProgErr> {
+    Err(String::from("Built without the gui feature, only --headless runs are available").into())
+}
@@ -0,0 +1,90 @@
+//! Klaus Dormann's 6502 test suites, from https://github.com/Klaus2m5/6502_65C02_functional_tests.
+//! The binaries go in `tests/roms`, see the README there. Until they're in place the tests
+//! are ignored, and fail if the binary is missing; run them with
+//! `cargo test --release --test dormann -- --ignored`
+use crustacean_6502_emulator::emulator::loader::{self, Format};
+use crustacean_6502_emulator::emulator::{Address, System};
+use std::path::Path;
+
+/// Both suites take well under this
+const MAX_CYCLES: usize = 200_000_000;
+
+/// How a run ended
+enum Trap {
+    /// An instruction jumped or branched to itself
+    At(Address),
+    OutOfCycles,
+    Error(Address, String),
+}
+
+/// Loads a 64 KiB image at $0000 and runs it from `start` until it traps
+fn run(file: &str, start: u16) -> (System, Trap) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/roms")
+        .join(file);
+    let data = std::fs::read(&path).unwrap_or_else(|e| {
+        panic!(
+            "Couldn't read {}: {}. See tests/roms/README.md",
+            path.display(),
+            e
+        )
+    });
+    let mut system = System::new();
+    loader::load(&mut *system.bus, Format::Raw(Address(0x0000)), &data, false)
+        .expect("Couldn't load the image");
+    // The reset vector points to a trap, the suites start elsewhere
+    system.init().unwrap();
+    system.registers.PC = Address(start as usize);
+    let trap = loop {
+        let pc = system.registers.PC;
+        if let Err(e) = system.step() {
+            break Trap::Error(pc, e.to_string());
+        }
+        if system.registers.PC == pc {
+            break Trap::At(pc);
+        }
+        if system.cycles > MAX_CYCLES {
+            break Trap::OutOfCycles;
+        }
+    };
+    (system, trap)
+}
+
+#[test]
+#[ignore = "needs tests/roms/6502_functional_test.bin"]
+fn functional_test() {
+    const SUCCESS: usize = 0x3469;
+    /// The number of the test that's running
+    const TEST_CASE: Address = Address(0x0200);
+    let (system, trap) = run("6502_functional_test.bin", 0x0400);
+    let test_case = system.bus.peek(TEST_CASE);
+    match trap {
+        Trap::At(pc) if *pc == SUCCESS => {}
+        Trap::At(pc) => panic!("Test ${:02X} failed, trapped at ${:04X}", test_case, *pc),
+        Trap::OutOfCycles => panic!("Test ${:02X} never finished", test_case),
+        Trap::Error(pc, e) => panic!("Test ${:02X} failed at ${:04X}: {}", test_case, *pc, e),
+    }
+}
+
+#[test]
+#[ignore = "needs tests/roms/6502_decimal_test.bin"]
+fn decimal_test() {
+    /// 0 when every ADC and SBC gave the right result and flags
+    const ERROR: Address = Address(0x000B);
+    let (system, trap) = run("6502_decimal_test.bin", 0x0200);
+    match trap {
+        Trap::At(_) => {}
+        Trap::OutOfCycles => panic!("The decimal test never finished"),
+        Trap::Error(pc, e) => panic!("The decimal test failed at ${:04X}: {}", *pc, e),
+    }
+    // The operands and results of the failing case come before ERROR
+    let state: Vec<u8> = (0x00..0x0B)
+        .map(|addr| system.bus.peek(Address(addr)))
+        .collect();
+    assert_eq!(
+        system.bus.peek(ERROR),
+        0,
+        "Wrong decimal result, zero page from $00: {:02X?}",
+        state
+    );
+}
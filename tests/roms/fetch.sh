@@ -0,0 +1,9 @@
+#!/bin/sh
+# Downloads the prebuilt Dormann binaries next to this script.
+# The decimal test has no prebuilt binary upstream, see README.md
+set -e
+cd "$(dirname "$0")"
+UPSTREAM=https://raw.githubusercontent.com/Klaus2m5/6502_65C02_functional_tests/master
+curl --fail --silent --show-error --location \
+    --output 6502_functional_test.bin \
+    "$UPSTREAM/bin_files/6502_functional_test.bin"
@@ -0,0 +1,190 @@
+//! Runs single instruction test vectors in the format of
+//! https://github.com/SingleStepTests/65x02 (`6502/v1/*.json`): each case sets up the
+//! registers and memory, steps once, and checks the registers, memory and every bus cycle:
+//! the address, the value and whether it was a read or a write, dummy accesses included.
+//!
+//! `tests/vectors/sample.json` checks the harness itself. The upstream vectors go in
+//! `tests/vectors/6502`, one `<opcode>.json` per opcode, see the README there. Until they're
+//! in place those tests are ignored; run them with `cargo test --test single_step -- --ignored`.
+//! Undocumented opcodes run with `IllegalOpcodes::Emulate`, JAM and the unstable ones are skipped
+use crustacean_6502_emulator::emulator::{
+    is_documented, is_stable, Address, Bus, IllegalOpcodes, Ram, System,
+};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Deserialize)]
+struct Case {
+    name: String,
+    initial: State,
+    #[serde(rename = "final")]
+    expected: State,
+    /// `[address, value, "read" | "write"]` for every cycle
+    cycles: Vec<(u16, u8, String)>,
+}
+
+#[derive(Deserialize)]
+struct State {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+type Cycles = Arc<Mutex<Vec<(u16, u8, String)>>>;
+
+/// RAM that logs every cycle the CPU spends on it
+struct Logger {
+    ram: Ram,
+    cycles: Cycles,
+}
+impl Logger {
+    fn log(&self, addr: Address, value: u8, access: &str) {
+        let cycle = (*addr as u16, value, access.to_string());
+        self.cycles.lock().unwrap().push(cycle);
+    }
+}
+impl Bus for Logger {
+    fn read(&mut self, addr: Address) -> u8 {
+        let value = self.ram.read(addr);
+        self.log(addr, value, "read");
+        value
+    }
+    fn write(&mut self, addr: Address, value: u8) {
+        self.ram.write(addr, value);
+        self.log(addr, value, "write");
+    }
+    fn peek(&self, addr: Address) -> u8 {
+        self.ram.peek(addr)
+    }
+    fn load(&mut self, start: Address, data: &[u8]) {
+        self.ram.load(start, data);
+    }
+}
+
+/// Every difference between the state after the step and the expected one
+fn run(case: &Case) -> Vec<String> {
+    let cycles = Cycles::default();
+    let mut system = System::with_bus(Box::new(Logger {
+        ram: Ram::new(),
+        cycles: cycles.clone(),
+    }));
+    system.illegal_opcodes = IllegalOpcodes::Emulate;
+    system.init().unwrap();
+    let initial = &case.initial;
+    for (addr, value) in &initial.ram {
+        system.bus.load(Address(*addr as usize), &[*value]);
+    }
+    system.registers.PC = Address(initial.pc as usize);
+    system.registers.S = initial.s;
+    system.registers.A = initial.a;
+    system.registers.X = initial.x;
+    system.registers.Y = initial.y;
+    system.registers.flags = initial.p;
+    system.cycles = 0;
+    cycles.lock().unwrap().clear();
+    if let Err(e) = system.step() {
+        return vec![e.to_string()];
+    }
+
+    let expected = &case.expected;
+    let r = &system.registers;
+    let mut differences = vec![];
+    let mut compare = |what: &str, found: String, expected: String| {
+        if found != expected {
+            differences.push(format!("{} is {}, not {}", what, found, expected));
+        }
+    };
+    let byte = |value: u8| format!("${:02X}", value);
+    compare(
+        "PC",
+        format!("${:04X}", *r.PC),
+        format!("${:04X}", expected.pc),
+    );
+    compare("S", byte(r.S), byte(expected.s));
+    compare("A", byte(r.A), byte(expected.a));
+    compare("X", byte(r.X), byte(expected.x));
+    compare("Y", byte(r.Y), byte(expected.y));
+    compare("P", byte(r.flags), byte(expected.p));
+    compare(
+        "Cycles",
+        system.cycles.to_string(),
+        case.cycles.len().to_string(),
+    );
+    for (addr, value) in &expected.ram {
+        let found = system.bus.peek(Address(*addr as usize));
+        compare(&format!("${:04X}", addr), byte(found), byte(*value));
+    }
+    let bus = |cycles: &[(u16, u8, String)]| {
+        let cycles: Vec<String> = cycles
+            .iter()
+            .map(|(addr, value, access)| format!("{} ${:04X} ${:02X}", access, addr, value))
+            .collect();
+        cycles.join(", ")
+    };
+    compare("Bus", bus(&cycles.lock().unwrap()), bus(&case.cycles));
+    differences
+}
+
+/// Runs every case in the file, describing the failed ones
+fn run_file(path: &Path) -> Vec<String> {
+    let text = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Couldn't read {}: {}", path.display(), e));
+    let cases: Vec<Case> = serde_json::from_str(&text)
+        .unwrap_or_else(|e| panic!("Couldn't parse {}: {}", path.display(), e));
+    cases
+        .iter()
+        .filter_map(|case| {
+            let differences = run(case);
+            if differences.is_empty() {
+                None
+            } else {
+                Some(format!("{}: {}", case.name, differences.join(", ")))
+            }
+        })
+        .collect()
+}
+
+fn vectors() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/vectors")
+}
+
+#[test]
+fn sample() {
+    let failures = run_file(&vectors().join("sample.json"));
+    assert!(failures.is_empty(), "{:#?}", failures);
+}
+
+/// Runs the file of every opcode, failing with the ones that don't pass
+fn run_opcodes<I: Iterator<Item = u8>>(codes: I) {
+    let mut failed = vec![];
+    for code in codes {
+        let path = vectors().join("6502").join(format!("{:02x}.json", code));
+        let failures = run_file(&path);
+        if !failures.is_empty() {
+            // The first few say enough about what's wrong
+            println!("${:02X}: {} failed", code, failures.len());
+            for failure in failures.iter().take(5) {
+                println!("  {}", failure);
+            }
+            failed.push(code);
+        }
+    }
+    assert!(failed.is_empty(), "Failing opcodes: {:02X?}", failed);
+}
+
+#[test]
+#[ignore = "needs the SingleStepTests vectors in tests/vectors/6502"]
+fn documented_opcodes() {
+    run_opcodes((0..=0xFF).filter(|code| is_documented(*code)));
+}
+
+#[test]
+#[ignore = "needs the SingleStepTests vectors in tests/vectors/6502"]
+fn undocumented_opcodes() {
+    run_opcodes((0..=0xFF).filter(|code| is_stable(*code)));
+}
@@ -0,0 +1,22 @@
+#!/bin/sh
+# Downloads the SingleStepTests 6502 vectors into 6502/ next to this script, keeping the
+# first CASES cases (100 by default) of each opcode so the files stay small enough to commit.
+#
+#     tests/vectors/fetch.sh [CASES]
+set -e
+cd "$(dirname "$0")"
+CASES=${1:-100}
+UPSTREAM=https://raw.githubusercontent.com/SingleStepTests/65x02/HEAD/6502/v1
+mkdir -p 6502
+for code in $(seq 0 255); do
+    file=$(printf '%02x.json' "$code")
+    curl --fail --silent --show-error --location --output "6502/$file" "$UPSTREAM/$file"
+    python3 -c '
+import json, sys
+path, cases = sys.argv[1], int(sys.argv[2])
+with open(path) as f:
+    trimmed = json.load(f)[:cases]
+with open(path, "w") as f:
+    f.write("[\n" + ",\n".join(json.dumps(c, separators=(",", ":")) for c in trimmed) + "\n]\n")
+' "6502/$file" "$CASES"
+done